@@ -22,13 +22,11 @@
 //! for longs and shorts to express their view
 
 // TODO: add weight stuff, and benchmark it
-// TODO: allow any sort of payoff
 // TODO: make documentation better
 // TODO: clean up code
 // TODO: check collateral redeeming cases, for now if pool is at a loss
 //       there is a race, and the first person to claim collateral takes
 //       more than the others (the others may end up with 0!)
-// TODO: Should I clean 0 balances to clear up storage?
 // TODO: move liquidation and all this to offchain worker
 
 #![cfg_attr(not(feature = "std"), no_std)]
@@ -36,16 +34,20 @@
 
 use frame_support::{
 	pallet_prelude::*,
-	traits::{Currency, ExistenceRequirement, OnUnbalanced, WithdrawReasons},
+	traits::{Currency, ExistenceRequirement, Imbalance, OnUnbalanced, ReservableCurrency, WithdrawReasons},
 	transactional,
 };
 use frame_system::pallet_prelude::*;
 use codec::FullCodec;
 
-use sp_arithmetic::Perquintill;
+use sp_arithmetic::{
+	biguint,
+	helpers_128bit::{multiply_by_rational, to_big_uint},
+	Perquintill,
+};
 use sp_runtime::{
-	traits::{AccountIdConversion, Zero},
-	FixedPointNumber, Permill, ModuleId, FixedU128,
+	traits::{AccountIdConversion, CheckedDiv, Saturating, UniqueSaturatedInto, Zero},
+	DispatchResult, FixedPointNumber, Permill, ModuleId, FixedU128, TypeId,
 };
 use sp_std::{convert::TryInto, result, vec::Vec, fmt::Debug,};
 
@@ -58,11 +60,14 @@ pub enum Change<Value> {
 	NewValue(Value),
 }
 
+pub mod migrations;
 mod mock;
+pub mod payoff;
 mod tests;
 pub mod weights;
 
 pub use module::*;
+pub use payoff::Payoff;
 pub use weights::WeightInfo;
 
 type PalletBalanceOf<T> = <<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
@@ -73,17 +78,389 @@ pub trait PriceProvider<T> {
 	fn get_price(currency_id: T) -> Option<FixedU128>;
 }
 
+/// Position tokens as seen by other pallets, e.g. one taking them as
+/// collateral. Tokens move under the same checks as `transfer_position_token`.
+pub trait PositionTokenRegistry<AccountId> {
+	type AssetId;
+
+	/// Owner and asset of a token
+	fn token(token_id: PositionTokenId) -> Option<(AccountId, Self::AssetId)>;
+
+	/// Balance and margin held by a token
+	fn holdings(token_id: PositionTokenId) -> Option<(i128, u128)>;
+
+	/// Give a token owned by `from` to `to`
+	fn transfer(token_id: PositionTokenId, from: &AccountId, to: &AccountId) -> DispatchResult;
+}
+
 /// Asset params
 #[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
 pub struct AssetParams {
 	pub initial_im_ratio: Permill,
 	pub liquidation_ratio: Permill,
 	pub transaction_fee: Permill,
+	/// How the oversubscribed side is filled
+	pub matching_policy: MatchingPolicy,
+	/// Maximum notional of the vault's position
+	pub vault_risk_limit: u128,
+	/// Value of one unit as a function of the price. Margin is always held
+	/// in `T::Currency`, shared by every market. With `Payoff::Inverse` a
+	/// unit pays in the underlying, so its notional, fees, PnL, margin
+	/// requirements and vault limit are all converted to `T::Currency` at
+	/// the price they are computed at.
+	pub payoff: Payoff,
+	/// Ratios of larger positions, sorted by increasing `min_notional`.
+	/// Each bracket's ratios apply to the part of a position's notional
+	/// between its `min_notional` and the next bracket, the ratios above to
+	/// the part below the first bracket.
+	pub brackets: Vec<RiskBracket>,
+}
+
+impl AssetParams {
+	/// IM and liquidation requirements of a position of `notional`, each
+	/// ratio applying only to the slice of the notional inside its bracket,
+	/// so that requirements grow without jumps at the boundaries. The IM
+	/// ratio of every slice is at least `im_floor`. Rounded up per slice.
+	pub fn requirements(&self, notional: u128, im_floor: Permill) -> (u128, u128) {
+		let mut tiers = sp_std::iter::once((0, self.initial_im_ratio, self.liquidation_ratio))
+			.chain(
+				self.brackets
+					.iter()
+					.map(|bracket| (bracket.min_notional, bracket.initial_im_ratio, bracket.liquidation_ratio)),
+			)
+			.peekable();
+		let (mut im, mut liquidation) = (0u128, 0u128);
+		while let Some((start, im_ratio, liquidation_ratio)) = tiers.next() {
+			if notional <= start {
+				break;
+			}
+			let end = tiers.peek().map_or(notional, |(next, _, _)| notional.min(*next));
+			let slice = end.saturating_sub(start);
+			im = im.saturating_add(im_ratio.max(im_floor).mul_ceil(slice));
+			liquidation = liquidation.saturating_add(liquidation_ratio.mul_ceil(slice));
+		}
+		(im, liquidation)
+	}
+
+	/// Whether the brackets are sorted by strictly increasing notional, the
+	/// ratios never decrease as positions grow, and the liquidation ratio of
+	/// each bracket is below its IM ratio
+	pub fn valid_brackets(&self) -> bool {
+		let mut previous = (0u128, self.initial_im_ratio, self.liquidation_ratio);
+		for bracket in self.brackets.iter() {
+			let (min_notional, im, liquidation) = previous;
+			if bracket.min_notional <= min_notional
+				|| bracket.initial_im_ratio < im
+				|| bracket.liquidation_ratio < liquidation
+				|| bracket.liquidation_ratio >= bracket.initial_im_ratio
+			{
+				return false;
+			}
+			previous = (bracket.min_notional, bracket.initial_im_ratio, bracket.liquidation_ratio);
+		}
+		true
+	}
+}
+
+/// Margin ratios of the positions of an asset whose notional is at least
+/// `min_notional`
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct RiskBracket {
+	pub min_notional: u128,
+	pub initial_im_ratio: Permill,
+	pub liquidation_ratio: Permill,
+}
+
+/// Storage layout version of the pallet
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Releases {
+	/// First release, with assets given by the `AssetIds` constant and
+	/// `AssetParams` holding ratios and the fee only
+	V1_0_0,
+	/// Assets in `ListedAssets`, matching policy and vault risk limit added
+	/// to `AssetParams`, and open interest tracked per asset
+	V2_0_0,
+	/// Payoff added to `AssetParams`
+	V3_0_0,
+	/// Risk brackets added to `AssetParams`
+	V4_0_0,
+}
+
+impl Default for Releases {
+	fn default() -> Self {
+		Releases::V1_0_0
+	}
+}
+
+/// A fee tier, applying to accounts whose rolling traded notional is at
+/// least `min_volume`
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct FeeTier {
+	pub min_volume: u128,
+	pub maker_fee: Permill,
+	pub taker_fee: Permill,
+}
+
+/// Whether a trade reduces (maker) or increases (taker) the imbalance
+/// between longs and shorts
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum Liquidity {
+	Maker,
+	Taker,
+}
+
+/// Share of the fees routed to the insurance fund and to the liquidity
+/// providers' reward pot, the remainder goes to the treasury
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct FeeSplit {
+	pub insurance: Permill,
+	pub rewards: Permill,
+}
+
+/// Amount of fees routed to each destination
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct FeeDistribution {
+	pub treasury: u128,
+	pub insurance: u128,
+	pub rewards: u128,
+	pub referrals: u128,
+}
+
+/// Share of the fees paid by a referred trader accruing to their referrer,
+/// and rebated to the trader
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct ReferralParams {
+	pub referrer_share: Permill,
+	pub trader_rebate: Permill,
+}
+
+/// How the oversubscribed side of an asset is filled
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum MatchingPolicy {
+	/// Pro-rata, rounding down
+	ProRata,
+	/// Pro-rata, allocating the rounding dust by largest remainder
+	LargestRemainder,
+	/// First in first out, by the block the position was last increased
+	Fifo,
+}
+
+impl Default for MatchingPolicy {
+	fn default() -> Self {
+		MatchingPolicy::ProRata
+	}
+}
+
+/// Identifier of a trigger order
+pub type OrderId = u64;
+
+/// Identifier of a position token
+pub type PositionTokenId = u64;
+
+/// Identifier of a queued margin withdrawal
+pub type WithdrawalId = u64;
+
+/// Limits an account puts on withdrawals from its margin
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct WithdrawalLimit<BlockNumber> {
+	/// Collateral that can be withdrawn right away per withdrawal period
+	pub cap: u128,
+	/// Number of blocks withdrawals over the cap are queued for, rejected if
+	/// not set
+	pub timelock: Option<BlockNumber>,
+}
+
+impl<BlockNumber: PartialOrd> WithdrawalLimit<BlockNumber> {
+	/// Whether this limit allows no more than `other`: a cap no larger, and
+	/// a timelock no shorter or none at all
+	pub fn is_stricter(&self, other: &Self) -> bool {
+		self.cap <= other.cap
+			&& match (&self.timelock, &other.timelock) {
+				(None, _) => true,
+				(Some(_), None) => false,
+				(Some(timelock), Some(other)) => timelock >= other,
+			}
+	}
+}
+
+/// A withdrawal over the cap of an account, which can be executed from
+/// `unlock_at`
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct PendingWithdrawal<BlockNumber> {
+	pub amount: u128,
+	pub unlock_at: BlockNumber,
+}
+
+/// What a delegate may do for the account that authorised it
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum DelegateAction {
+	/// Change positions, place and cancel orders, and add collateral
+	Trade,
+	/// Take collateral out of the margin, back to the account
+	Withdraw,
+}
+
+/// Actions a delegate may take for an account
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct Delegation<AssetId, BlockNumber> {
+	pub actions: Vec<DelegateAction>,
+	/// Assets the delegate may act on, all of them if empty
+	pub assets: Vec<AssetId>,
+	/// Last block at which the delegation can be used
+	pub expiry: Option<BlockNumber>,
+}
+
+/// A position wrapped into a token, whose ownership is tracked by this
+/// pallet and exposed to others through `PositionTokenRegistry`
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct PositionToken<AccountId, AssetId> {
+	pub owner: AccountId,
+	pub asset: AssetId,
+}
+
+/// Side of the trigger price on which a trigger order fires
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum TriggerDirection {
+	/// When the price is at or above the trigger price
+	Above,
+	/// When the price is at or below the trigger price
+	Below,
+}
+
+impl TriggerDirection {
+	/// Whether `price` is on the firing side of `trigger_price`
+	pub fn crossed(&self, trigger_price: FixedU128, price: FixedU128) -> bool {
+		match self {
+			TriggerDirection::Above => price >= trigger_price,
+			TriggerDirection::Below => price <= trigger_price,
+		}
+	}
+}
+
+/// An order to trade `amount` once the price crosses `trigger_price`, such
+/// as a stop-loss or a take-profit
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct TriggerOrder<AccountId, Balance> {
+	pub account: AccountId,
+	pub trigger_price: FixedU128,
+	pub direction: TriggerDirection,
+	pub amount: i128,
+	/// Only reduce the position, `amount` being capped to its size
+	pub reduce_only: bool,
+	/// Reserved from `account` until the order is removed
+	pub deposit: Balance,
+}
+
+/// Assets whose positions on opposite sides offset each other's margin
+/// requirement. The requirement of the smaller side of the group is
+/// reduced by `offset`, so 0 margins the legs as unrelated and 100% only
+/// margins the net risk.
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct OffsetGroup<AssetId> {
+	pub assets: Vec<AssetId>,
+	pub offset: Permill,
+}
+
+/// Aggregate long and short balances of an asset
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct OpenInterest {
+	pub longs: u128,
+	pub shorts: u128,
+}
+
+/// Average entry price and cumulative realised PnL of a position
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+pub struct PositionInfo {
+	pub entry_price: FixedU128,
+	pub realised_pnl: i128,
+}
+
+/// Summary of the position of an account in an asset
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub struct AccountPosition<AssetId> {
+	pub asset: AssetId,
+	pub balance: i128,
+	pub inventory: i128,
+	pub entry_price: FixedU128,
+	pub realised_pnl: i128,
+	pub unrealised_pnl: i128,
+	/// Maximum leverage set by the account, if any
+	pub leverage: Option<FixedU128>,
+}
+
+/// Payload of the `Liquidated` and `Unwound` events. New versions are added
+/// as new variants so indexers can keep decoding old events
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum LiquidationPayload<AccountId, AssetId> {
+	V1 {
+		account: AccountId,
+		asset: AssetId,
+		/// Balance before the liquidation
+		balance: i128,
+		/// Inventory before the liquidation
+		inventory: i128,
+		price: FixedU128,
+		margin: u128,
+	},
+}
+
+/// Payload of the `Matched` event
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum MatchPayload<AssetId> {
+	V1 {
+		asset: AssetId,
+		longs: u128,
+		shorts: u128,
+		/// Size matched on each side
+		matched: u128,
+		price: FixedU128,
+	},
+}
+
+/// Payload of the `MarginMarked` event
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum MarginMarkPayload<AccountId, AssetId> {
+	V1 {
+		account: AccountId,
+		asset: AssetId,
+		inventory: i128,
+		old_price: FixedU128,
+		new_price: FixedU128,
+		/// Change in margin, before flooring it at 0
+		pnl: i128,
+		margin: u128,
+	},
+}
+
+/// Payload of the `Traded` event, with the fee charged for the trade
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum TradePayload<AccountId, AssetId> {
+	V1 {
+		account: AccountId,
+		asset: AssetId,
+		amount: i128,
+		notional: u128,
+		price: FixedU128,
+		fee: u128,
+		rate: Permill,
+		liquidity: Liquidity,
+	},
+}
+
+/// Payload of the `PriceUpdated` event
+#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+pub enum PricePayload<AssetId> {
+	V1 {
+		asset: AssetId,
+		old_price: Option<FixedU128>,
+		new_price: FixedU128,
+	},
 }
 
 // typedef to help polkadot.js disambiguate Change with different generic
 // parameters
 type ChangePermill = Change<Permill>;
+type ChangeBrackets = Change<Vec<RiskBracket>>;
 
 #[frame_support::pallet]
 pub mod module {
@@ -104,12 +481,8 @@ pub mod module {
 		#[pallet::constant]
 		type ModuleId: Get<ModuleId>;
 
-		/// The list of valid asset types
-		#[pallet::constant]
-		type AssetIds: Get<Vec<Self::AssetId>>;
-
 		/// The currency type in which fees will be paid.
-		type Currency: Currency<Self::AccountId>;
+		type Currency: ReservableCurrency<Self::AccountId>;
 
 		/// The treasury for funds
 		type Treasury: OnUnbalanced<NegativeImbalanceOf<Self>>;
@@ -117,6 +490,53 @@ pub mod module {
 		/// Price provider, TODO work on that to make it more generic
 		type PriceSource: PriceProvider<Self::AssetId>;
 
+		/// Number of blocks in a traded volume bucket, typically a day. 0 is
+		/// treated as 1.
+		#[pallet::constant]
+		type VolumeBucketLength: Get<Self::BlockNumber>;
+
+		/// Number of buckets in the rolling traded volume window used for
+		/// fee tiers, typically 30
+		#[pallet::constant]
+		type VolumeBuckets: Get<u32>;
+
+		/// Number of blocks a liquidity provider waits between requesting a
+		/// withdrawal from the vault and withdrawing
+		#[pallet::constant]
+		type VaultCooldown: Get<Self::BlockNumber>;
+
+		/// Accounts without positions whose margin is below this are closed,
+		/// and their margin sent to the treasury
+		#[pallet::constant]
+		type MinimumMargin: Get<u128>;
+
+		/// Deposit reserved from an account while it has a margin entry
+		#[pallet::constant]
+		type StorageDeposit: Get<PalletBalanceOf<Self>>;
+
+		/// Number of blocks before its expiry over which the settlement price
+		/// of a dated future is averaged
+		#[pallet::constant]
+		type SettlementWindow: Get<Self::BlockNumber>;
+
+		/// Maximum number of open trigger orders per account
+		#[pallet::constant]
+		type MaxTriggerOrders: Get<u32>;
+
+		/// Deposit reserved from an account for each of its open trigger
+		/// orders, which are scanned every block
+		#[pallet::constant]
+		type TriggerOrderDeposit: Get<PalletBalanceOf<Self>>;
+
+		/// Number of blocks over which withdrawals count against the cap of
+		/// an account, typically a day. 0 disables the caps.
+		#[pallet::constant]
+		type WithdrawalPeriod: Get<Self::BlockNumber>;
+
+		/// Maximum number of queued withdrawals per account
+		#[pallet::constant]
+		type MaxPendingWithdrawals: Get<u32>;
+
 		/// Weight information for the extrinsics in this module.
 		type WeightInfo: WeightInfo;
 	}
@@ -137,24 +557,401 @@ pub mod module {
 		BadAssetID,
 		/// Bad parameters being set
 		BadIMParameters,
+		/// Asset is already listed
+		AssetAlreadyListed,
+		/// Fee tiers are not sorted by increasing volume
+		BadFeeTiers,
+		/// Fee split adds up to more than 100%
+		BadFeeSplit,
+		/// Referrer is already registered
+		ReferrerAlreadySet,
+		/// Cannot refer oneself
+		SelfReferral,
+		/// Referral shares add up to more than 100%
+		BadReferralParams,
+		/// No rewards to claim
+		NoRewards,
+		/// Not enough vault shares
+		NotEnoughShares,
+		/// Vault withdrawal cooldown is not over yet
+		VaultCooldownNotOver,
+		/// No vault withdrawal was requested
+		NoPendingWithdrawal,
+		/// The vault has shares outstanding but no margin left
+		VaultInsolvent,
+		/// The market is halted
+		MarketIsHalted,
+		/// The account is quarantined
+		AccountIsQuarantined,
+		/// The market is not halted
+		MarketNotHalted,
+		/// The account is not quarantined
+		AccountNotQuarantined,
+		/// The payoff of the asset is not defined at its price
+		InvalidPrice,
+		/// The dated future has expired
+		MarketExpired,
+		/// The expiry is not in the future
+		InvalidExpiry,
+		/// The market has no market to roll positions into
+		NoRollTarget,
+		/// There is no position to roll
+		NothingToRoll,
+		/// An offset group has fewer than two assets, an unlisted asset, or
+		/// an asset already in another group
+		InvalidOffsetGroup,
+		/// The leverage is below 1
+		InvalidLeverage,
+		/// The trigger order has a zero amount or trigger price
+		InvalidTriggerOrder,
+		/// The account has too many open trigger orders
+		TooManyTriggerOrders,
+		/// No such trigger order for the account
+		TriggerOrderNotFound,
+		/// A reduce only order would not reduce the position
+		NotReducing,
+		/// The amount is zero, larger than the position or on the other
+		/// side, or the position is sent to its owner or to an account of
+		/// the pallet
+		InvalidTransfer,
+		/// The recipient has not approved receiving this position from the
+		/// sender
+		TransferNotApproved,
+		/// No such position token
+		PositionTokenNotFound,
+		/// The caller is not allowed to act for the account
+		NotDelegated,
+		/// The caller does not own the position token
+		NotTokenOwner,
+		/// The batch is empty or trades an asset more than once
+		InvalidBatch,
+		/// The withdrawal is over the cap of the account, which has no
+		/// timelock, or margin moved out with a position is over the cap
+		WithdrawalLimitExceeded,
+		/// Too many withdrawals are queued for the account
+		TooManyPendingWithdrawals,
+		/// No such queued withdrawal
+		WithdrawalNotFound,
+		/// The timelock of the withdrawal is not over yet
+		WithdrawalLocked,
+		/// The asset has more entries than the delisting was weighed for
+		TooManyEntries,
 	}
 
 	#[pallet::event]
 	#[pallet::generate_deposit(pub(crate) fn deposit_event)]
 	pub enum Event<T: Config> {
-		/// Emitted when collateral is updated by \[i128\]
-		CollateralUpdated(i128),
-		/// Emitted when the balance of \[T::AccountId\] is updated to
-		/// \[i128\]
-		BalanceUpdated(T::AccountId, i128),
+		/// Emitted when collateral of \[T::AccountId\] is updated by \[i128\]
+		/// while trading \[AssetId\], leaving a margin of \[u128\]
+		CollateralUpdated(T::AccountId, T::AssetId, i128, u128),
+		/// Emitted when the balance of \[T::AccountId\] in \[AssetId\] is
+		/// updated to \[i128\]
+		BalanceUpdated(T::AccountId, T::AssetId, i128),
 		/// Emitted when IM ratio of \[AssetId\] is updated by \[Permill\]
 		InitialIMRatioUpdated(T::AssetId, Permill),
 		/// Emitted when liquidation ratio of \[AssetId\] is updated by \[Permill\]
 		LiquidationRatioUpdated(T::AssetId, Permill),
 		/// Emitted when transaction fee of \[AssetId\] is updated by \[Permill\]
 		TransactionFeeUpdated(T::AssetId, Permill),
+		/// Emitted when the risk brackets of \[AssetId\] are updated to
+		/// \[Vec<RiskBracket>\]
+		RiskBracketsUpdated(T::AssetId, Vec<RiskBracket>),
+		/// Emitted when \[AssetId\] is listed with \[AssetParams\]
+		AssetListed(T::AssetId, AssetParams),
+		/// Emitted when \[AssetId\] is delisted
+		AssetDelisted(T::AssetId),
+		/// Emitted when the position of \[T::AccountId\] in \[AssetId\] of
+		/// size \[i128\] is settled
+		PositionSettled(T::AccountId, T::AssetId, i128),
+		/// Emitted when the fee tiers are updated to \[Vec<FeeTier>\]
+		FeeTiersUpdated(Vec<FeeTier>),
+		/// Emitted when an account trades, paying a fee \[TradePayload\]
+		Traded(TradePayload<T::AccountId, T::AssetId>),
+		/// Emitted when the fee split is updated to \[FeeSplit\]
+		FeeSplitUpdated(FeeSplit),
+		/// Emitted at the end of a block with the fees routed to each
+		/// destination during that block \[FeeDistribution\]
+		FeesDistributed(FeeDistribution),
+		/// Emitted when \[T::AccountId\] registers \[T::AccountId\] as referrer
+		ReferrerRegistered(T::AccountId, T::AccountId),
+		/// Emitted when the referral parameters are updated to
+		/// \[ReferralParams\]
+		ReferralParamsUpdated(ReferralParams),
+		/// Emitted when referrer \[T::AccountId\] earns \[u128\] from the
+		/// fees paid by \[T::AccountId\]
+		ReferralRewardAccrued(T::AccountId, T::AccountId, u128),
+		/// Emitted when \[T::AccountId\] earns a rebate of \[u128\]
+		RebateAccrued(T::AccountId, u128),
+		/// Emitted when \[T::AccountId\] claims \[u128\] of rewards
+		RewardsClaimed(T::AccountId, u128),
+		/// Emitted when the vault risk limit of \[AssetId\] is set to
+		/// \[u128\]
+		VaultRiskLimitUpdated(T::AssetId, u128),
+		/// Emitted when \[T::AccountId\] deposits \[u128\] into the vault
+		/// for \[u128\] shares
+		VaultDeposited(T::AccountId, u128, u128),
+		/// Emitted when \[T::AccountId\] requests to withdraw \[u128\]
+		/// shares, withdrawable from block \[T::BlockNumber\]
+		VaultWithdrawalRequested(T::AccountId, u128, T::BlockNumber),
+		/// Emitted when \[T::AccountId\] withdraws \[u128\] from the vault
+		/// by redeeming \[u128\] shares
+		VaultWithdrawn(T::AccountId, u128, u128),
+		/// Emitted when \[u128\] of rewards are credited to the vault
+		VaultRewardsCollected(u128),
+		/// Emitted when the matching policy of \[AssetId\] is set to
+		/// \[MatchingPolicy\]
+		MatchingPolicyUpdated(T::AssetId, MatchingPolicy),
+		/// Emitted when the inventory of \[T::AccountId\] in \[AssetId\] is
+		/// filled to \[i128\], with an average entry price of \[FixedU128\]
+		/// and a realised PnL of \[i128\]
+		PositionUpdated(T::AccountId, T::AssetId, i128, FixedU128, i128),
+		/// Emitted when \[T::AccountId\] is quarantined after its margin
+		/// could not be computed
+		AccountQuarantined(T::AccountId),
+		/// Emitted when \[T::AccountId\] is released from quarantine
+		AccountReleased(T::AccountId),
+		/// Emitted when \[AssetId\] is halted after its interest could not be
+		/// matched
+		MarketHalted(T::AssetId),
+		/// Emitted when \[AssetId\] is resumed
+		MarketResumed(T::AssetId),
+		/// Emitted when \[T::AccountId\] is closed, sending its dust margin
+		/// of \[u128\] to the treasury
+		DustSwept(T::AccountId, u128),
+		/// Emitted when a position is liquidated \[LiquidationPayload\]
+		Liquidated(LiquidationPayload<T::AccountId, T::AssetId>),
+		/// Emitted when the open interest of a position is removed
+		/// \[LiquidationPayload\]
+		Unwound(LiquidationPayload<T::AccountId, T::AssetId>),
+		/// Emitted when the interest of an asset is matched \[MatchPayload\]
+		Matched(MatchPayload<T::AssetId>),
+		/// Emitted when the margin of an account is marked to a new price
+		/// \[MarginMarkPayload\]
+		MarginMarked(MarginMarkPayload<T::AccountId, T::AssetId>),
+		/// Emitted when the price of an asset is updated \[PricePayload\]
+		PriceUpdated(PricePayload<T::AssetId>),
+		/// Emitted when \[AssetId\] is set to expire at \[T::BlockNumber\],
+		/// rolling into \[AssetId\], or made perpetual if `None`
+		ExpiryUpdated(T::AssetId, Option<T::BlockNumber>, Option<T::AssetId>),
+		/// Emitted when the dated future \[AssetId\] is settled at
+		/// \[FixedU128\]
+		MarketSettled(T::AssetId, FixedU128),
+		/// Emitted when \[T::AccountId\] rolls its position of \[i128\] from
+		/// \[AssetId\] into \[AssetId\]
+		PositionRolled(T::AccountId, i128, T::AssetId, T::AssetId),
+		/// Emitted when the offset groups are set to
+		/// \[Vec<OffsetGroup<AssetId>>\]
+		OffsetGroupsUpdated(Vec<OffsetGroup<T::AssetId>>),
+		/// Emitted when \[T::AccountId\] sets its maximum leverage in
+		/// \[AssetId\] to \[FixedU128\], or removes it if `None`
+		LeverageUpdated(T::AccountId, T::AssetId, Option<FixedU128>),
+		/// Emitted when \[T::AccountId\] places the trigger order
+		/// \[OrderId\] in \[AssetId\]
+		TriggerOrderPlaced(T::AccountId, T::AssetId, OrderId),
+		/// Emitted when \[T::AccountId\] cancels the trigger order
+		/// \[OrderId\] in \[AssetId\]
+		TriggerOrderCancelled(T::AccountId, T::AssetId, OrderId),
+		/// Emitted when the trigger order \[OrderId\] of \[T::AccountId\] in
+		/// \[AssetId\] fires at \[FixedU128\]
+		TriggerOrderTriggered(T::AccountId, T::AssetId, OrderId, FixedU128),
+		/// Emitted when the trigger order \[OrderId\] of \[T::AccountId\] in
+		/// \[AssetId\] trades \[i128\]
+		TriggerOrderExecuted(T::AccountId, T::AssetId, OrderId, i128),
+		/// Emitted when the trigger order \[OrderId\] of \[T::AccountId\] in
+		/// \[AssetId\] fails to trade with \[DispatchError\]
+		TriggerOrderFailed(T::AccountId, T::AssetId, OrderId, DispatchError),
+		/// Emitted when \[T::AccountId\] transfers \[i128\] of its position in
+		/// \[AssetId\] to \[T::AccountId\] along with a margin of \[u128\]
+		PositionTransferred(T::AccountId, T::AssetId, i128, T::AccountId, u128),
+		/// Emitted when \[T::AccountId\] approves receiving from
+		/// \[T::AccountId\] up to \[i128\] of a position in \[AssetId\]
+		PositionTransferApproved(T::AccountId, T::AccountId, T::AssetId, i128),
+		/// Emitted when \[T::AccountId\] wraps into the token
+		/// \[PositionTokenId\] a position in \[AssetId\] of \[i128\]
+		PositionWrapped(T::AccountId, PositionTokenId, T::AssetId, i128),
+		/// Emitted when the token \[PositionTokenId\] is given by
+		/// \[T::AccountId\] to \[T::AccountId\]
+		PositionTokenTransferred(PositionTokenId, T::AccountId, T::AccountId),
+		/// Emitted when \[T::AccountId\] unwraps the token
+		/// \[PositionTokenId\]
+		PositionUnwrapped(T::AccountId, PositionTokenId),
+		/// Emitted when \[T::AccountId\] authorises \[T::AccountId\] to act
+		/// for it as \[Delegation\]
+		DelegationUpdated(T::AccountId, T::AccountId, Delegation<T::AssetId, T::BlockNumber>),
+		/// Emitted when \[T::AccountId\] revokes its delegate
+		/// \[T::AccountId\]
+		DelegationRevoked(T::AccountId, T::AccountId),
+		/// Emitted when \[T::AccountId\] sets its withdrawal limit to
+		/// \[Option<WithdrawalLimit>\]
+		WithdrawalLimitUpdated(T::AccountId, Option<WithdrawalLimit<T::BlockNumber>>),
+		/// Emitted when \[T::AccountId\] loosens its withdrawal limit to
+		/// \[Option<WithdrawalLimit>\] from \[T::BlockNumber\]
+		WithdrawalLimitChangeQueued(T::AccountId, Option<WithdrawalLimit<T::BlockNumber>>, T::BlockNumber),
+		/// Emitted when a withdrawal of \[T::AccountId\] over its cap is
+		/// queued \[WithdrawalId, u128, T::BlockNumber\]
+		WithdrawalQueued(T::AccountId, WithdrawalId, u128, T::BlockNumber),
+		/// Emitted when \[T::AccountId\] executes its queued withdrawal
+		/// \[WithdrawalId, u128\]
+		WithdrawalExecuted(T::AccountId, WithdrawalId, u128),
+		/// Emitted when \[T::AccountId\] cancels its queued withdrawal
+		/// \[WithdrawalId\]
+		WithdrawalCancelled(T::AccountId, WithdrawalId),
 	}
 
+	/// Accounts whose margin could not be marked or checked, left untouched
+	/// by the hooks until released
+	#[pallet::storage]
+	#[pallet::getter(fn quarantined)]
+	pub type Quarantined<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, bool, ValueQuery>;
+
+	/// Price the inventory of a quarantined account was last marked at in
+	/// each asset, marked from when it changes or the account is released
+	#[pallet::storage]
+	#[pallet::getter(fn quarantine_price)]
+	pub type QuarantinePrices<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AccountId, Twox64Concat, T::AssetId, FixedU128, OptionQuery>;
+
+	/// Markets whose interest could not be matched, left untouched by the
+	/// hooks until resumed
+	#[pallet::storage]
+	#[pallet::getter(fn market_halted)]
+	pub type HaltedMarkets<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, bool, ValueQuery>;
+
+	/// Block at which each dated future expires, and the market its
+	/// positions can be rolled into
+	#[pallet::storage]
+	#[pallet::getter(fn expiry)]
+	pub type Expiries<T: Config> =
+		StorageMap<_, Twox64Concat, T::AssetId, (T::BlockNumber, Option<T::AssetId>), OptionQuery>;
+
+	/// Sum and number of the prices of each dated future sampled over its
+	/// settlement window, and the block of the last sample
+	#[pallet::storage]
+	pub(crate) type SettlementSamples<T: Config> =
+		StorageMap<_, Twox64Concat, T::AssetId, (FixedU128, u32, T::BlockNumber), ValueQuery>;
+
+	/// Price at which each expired dated future was settled
+	#[pallet::storage]
+	#[pallet::getter(fn settlement_price)]
+	pub type SettlementPrices<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, FixedU128, OptionQuery>;
+
+	/// Groups of assets margined on their net risk. Assets in no group are
+	/// margined on their own.
+	#[pallet::storage]
+	#[pallet::getter(fn offset_groups)]
+	pub type OffsetGroups<T: Config> = StorageValue<_, Vec<OffsetGroup<T::AssetId>>, ValueQuery>;
+
+	/// Maximum leverage each account allows itself in each asset, enforced
+	/// as a floor on its IM ratio
+	#[pallet::storage]
+	#[pallet::getter(fn leverage)]
+	pub type Leverages<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, FixedU128, OptionQuery>;
+
+	/// Open trigger orders of each asset
+	#[pallet::storage]
+	#[pallet::getter(fn trigger_order)]
+	pub type TriggerOrders<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, OrderId, TriggerOrder<T::AccountId, PalletBalanceOf<T>>, OptionQuery>;
+
+	/// Number of open trigger orders of each account
+	#[pallet::storage]
+	#[pallet::getter(fn trigger_order_count)]
+	pub type TriggerOrderCount<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, u32, ValueQuery>;
+
+	#[pallet::storage]
+	pub(crate) type NextOrderId<T: Config> = StorageValue<_, OrderId, ValueQuery>;
+
+	/// Owner and asset of each position token. The position itself is held
+	/// by the token's account.
+	#[pallet::storage]
+	#[pallet::getter(fn position_token)]
+	pub type PositionTokens<T: Config> =
+		StorageMap<_, Twox64Concat, PositionTokenId, PositionToken<T::AccountId, T::AssetId>, OptionQuery>;
+
+	#[pallet::storage]
+	pub(crate) type NextPositionTokenId<T: Config> = StorageValue<_, PositionTokenId, ValueQuery>;
+
+	/// Positions each account accepts to receive through
+	/// `transfer_position`, by sender: the asset and the largest amount
+	/// left, on the side of the position
+	#[pallet::storage]
+	#[pallet::getter(fn position_approval)]
+	pub type PositionApprovals<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AccountId, Twox64Concat, T::AccountId, (T::AssetId, i128), OptionQuery>;
+
+	/// Delegates of each account and what they may do
+	#[pallet::storage]
+	#[pallet::getter(fn delegation)]
+	pub type Delegations<T: Config> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		Twox64Concat,
+		T::AccountId,
+		Delegation<T::AssetId, T::BlockNumber>,
+		OptionQuery,
+	>;
+
+	/// Limits each account puts on withdrawals from its margin
+	#[pallet::storage]
+	#[pallet::getter(fn withdrawal_limit)]
+	pub type WithdrawalLimits<T: Config> =
+		StorageMap<_, Twox64Concat, T::AccountId, WithdrawalLimit<T::BlockNumber>, OptionQuery>;
+
+	/// Looser withdrawal limits set by each account, and the block from
+	/// which they apply
+	#[pallet::storage]
+	#[pallet::getter(fn pending_withdrawal_limit)]
+	pub type PendingWithdrawalLimits<T: Config> = StorageMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		(Option<WithdrawalLimit<T::BlockNumber>>, T::BlockNumber),
+		OptionQuery,
+	>;
+
+	/// Collateral each account withdrew within the cap, decaying linearly
+	/// over a withdrawal period, and the block it was last updated at
+	#[pallet::storage]
+	#[pallet::getter(fn withdrawn_collateral)]
+	pub(crate) type WithdrawnCollateral<T: Config> =
+		StorageMap<_, Twox64Concat, T::AccountId, (T::BlockNumber, u128), ValueQuery>;
+
+	/// Withdrawals over the cap of each account, waiting for their timelock
+	#[pallet::storage]
+	#[pallet::getter(fn pending_withdrawal)]
+	pub type PendingWithdrawals<T: Config> = StorageDoubleMap<
+		_,
+		Twox64Concat,
+		T::AccountId,
+		Twox64Concat,
+		WithdrawalId,
+		PendingWithdrawal<T::BlockNumber>,
+		OptionQuery,
+	>;
+
+	/// Number of queued withdrawals of each account
+	#[pallet::storage]
+	#[pallet::getter(fn pending_withdrawal_count)]
+	pub type PendingWithdrawalCount<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, u32, ValueQuery>;
+
+	#[pallet::storage]
+	pub(crate) type NextWithdrawalId<T: Config> = StorageValue<_, WithdrawalId, ValueQuery>;
+
+	/// Storage deposit reserved from each account with a margin entry
+	#[pallet::storage]
+	#[pallet::getter(fn storage_deposit)]
+	pub type StorageDeposits<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, PalletBalanceOf<T>, OptionQuery>;
+
+	/// Storage layout version, used to run migrations on runtime upgrade
+	#[pallet::storage]
+	pub(crate) type StorageVersion<T: Config> = StorageValue<_, Releases, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn listed_assets)]
+	pub type ListedAssets<T: Config> = StorageValue<_, Vec<T::AssetId>, ValueQuery>;
+
 	#[pallet::storage]
 	#[pallet::getter(fn collateral_params)]
 	pub type CollateralParams<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, AssetParams, ValueQuery>;
@@ -169,6 +966,11 @@ pub mod module {
 	pub(crate) type Inventory<T: Config> =
 		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, i128, ValueQuery>;
 
+	#[pallet::storage]
+	#[pallet::getter(fn position)]
+	pub type Positions<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, PositionInfo, ValueQuery>;
+
 	#[pallet::storage]
 	#[pallet::getter(fn margin)]
 	pub(crate) type Margin<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, u128, ValueQuery>;
@@ -177,6 +979,92 @@ pub mod module {
 	#[pallet::getter(fn price0)]
 	pub(crate) type Price0<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, FixedU128, OptionQuery>;
 
+	/// Fee tiers sorted by increasing `min_volume`
+	#[pallet::storage]
+	#[pallet::getter(fn fee_tiers)]
+	pub type FeeTiers<T: Config> = StorageValue<_, Vec<FeeTier>, ValueQuery>;
+
+	/// Shares of the fees sent to the insurance fund and the reward pot, the
+	/// rest going to the treasury
+	#[pallet::storage]
+	#[pallet::getter(fn fee_split)]
+	pub type FeeSplitParams<T: Config> = StorageValue<_, FeeSplit, ValueQuery>;
+
+	/// Fees routed to each destination during the current block
+	#[pallet::storage]
+	#[pallet::getter(fn block_fees)]
+	pub type BlockFees<T: Config> = StorageValue<_, FeeDistribution, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn referrer_of)]
+	pub type Referrers<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, T::AccountId, OptionQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn referral_params)]
+	pub type ReferralParameters<T: Config> = StorageValue<_, ReferralParams, ValueQuery>;
+
+	/// Referral rewards and rebates not yet claimed
+	#[pallet::storage]
+	#[pallet::getter(fn pending_rewards)]
+	pub type PendingRewards<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, u128, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn open_interest)]
+	pub type OpenInterests<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, OpenInterest, ValueQuery>;
+
+	/// Open interest at the time of the last match
+	#[pallet::storage]
+	pub(crate) type LastMatch<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, OpenInterest, ValueQuery>;
+
+	/// Whether a balance of the asset changed since the last match
+	#[pallet::storage]
+	#[pallet::getter(fn needs_matching)]
+	pub(crate) type NeedsMatching<T: Config> = StorageMap<_, Twox64Concat, T::AssetId, bool, ValueQuery>;
+
+	/// Accounts whose balance changed since the last match
+	#[pallet::storage]
+	pub(crate) type ChangedAccounts<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, (), OptionQuery>;
+
+	/// Block at which each position was last increased, used for time
+	/// priority
+	#[pallet::storage]
+	#[pallet::getter(fn order_block)]
+	pub(crate) type OrderBlock<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, T::BlockNumber, ValueQuery>;
+
+	/// Sequence number of the last increase of each position, breaking ties
+	/// between positions increased in the same block. Positions last
+	/// increased before it was recorded come first within their block.
+	#[pallet::storage]
+	#[pallet::getter(fn order_sequence)]
+	pub(crate) type OrderSequence<T: Config> =
+		StorageDoubleMap<_, Twox64Concat, T::AssetId, Twox64Concat, T::AccountId, u64, ValueQuery>;
+
+	#[pallet::storage]
+	pub(crate) type NextOrderSequence<T: Config> = StorageValue<_, u64, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn vault_shares)]
+	pub type VaultShares<T: Config> = StorageMap<_, Twox64Concat, T::AccountId, u128, ValueQuery>;
+
+	#[pallet::storage]
+	#[pallet::getter(fn total_vault_shares)]
+	pub type TotalVaultShares<T: Config> = StorageValue<_, u128, ValueQuery>;
+
+	/// Shares requested to be withdrawn from the vault, and the block from
+	/// which they can be withdrawn
+	#[pallet::storage]
+	#[pallet::getter(fn vault_withdrawal)]
+	pub type VaultWithdrawals<T: Config> =
+		StorageMap<_, Twox64Concat, T::AccountId, (u128, T::BlockNumber), OptionQuery>;
+
+	/// Traded notional per account, bucketed by `VolumeBucketLength` blocks
+	#[pallet::storage]
+	#[pallet::getter(fn traded_volume)]
+	pub(crate) type TradedVolume<T: Config> =
+		StorageMap<_, Twox64Concat, T::AccountId, Vec<(T::BlockNumber, u128)>, ValueQuery>;
+
 	#[pallet::genesis_config]
 	pub struct GenesisConfig<T: Config> {
 		#[allow(clippy::type_complexity)]
@@ -204,9 +1092,12 @@ pub mod module {
 							initial_im_ratio: *initial_im_ratio,
 							liquidation_ratio: *liquidation_ratio,
 							transaction_fee: *transaction_fee,
+							..Default::default()
 						},
 					);
+					ListedAssets::<T>::append(id);
 				});
+			StorageVersion::<T>::put(Releases::V4_0_0);
 		}
 	}
 
@@ -215,19 +1106,54 @@ pub mod module {
 
 	#[pallet::hooks]
 	impl<T: Config> Hooks<T::BlockNumber> for Pallet<T> {
-		fn on_initialize(_n: T::BlockNumber) -> Weight {
+		fn on_initialize(n: T::BlockNumber) -> Weight {
 			// TODO: this is called multiple times and not just at block start
-			for currency_id in T::AssetIds::get() {
-				Self::update_margin(currency_id);
-				Self::match_interest(currency_id);
+			BlockFees::<T>::kill();
+			// Each scan returns its weight, charged per account or order it
+			// went through
+			let mut weight = T::DbWeight::get().reads_writes(1, 1);
+			for currency_id in Self::listed_assets() {
+				weight = weight.saturating_add(T::DbWeight::get().reads(3));
+				if Self::market_halted(currency_id) || Self::settlement_price(currency_id).is_some() {
+					continue;
+				}
+				let expiry = Self::expiry(currency_id).map(|(expiry, _)| expiry);
+				if expiry.map_or(false, |expiry| n >= expiry) {
+					weight = weight.saturating_add(Self::settle(currency_id));
+					continue;
+				}
+				weight = weight.saturating_add(Self::update_margin(currency_id));
+				weight = weight.saturating_add(Self::execute_trigger_orders(currency_id));
+				if expiry.map_or(false, |expiry| n.saturating_add(T::SettlementWindow::get()) >= expiry) {
+					Self::sample_settlement_price(currency_id, n);
+					weight = weight.saturating_add(T::DbWeight::get().reads_writes(2, 1));
+				}
+				weight = weight.saturating_add(Self::match_interest(currency_id));
 			}
-			Self::liquidate(); // TODO, liquidate should run before match_interest
-				   // TODO change this to weightinfo, check cdp-engine
-			10
+			// TODO, liquidate should run before match_interest
+			weight.saturating_add(Self::liquidate())
+		}
+
+		/// This version of FRAME has no `pre_upgrade`/`post_upgrade` hooks, so
+		/// with `try-runtime` the checks run around the migration, aborting
+		/// the upgrade if they fail
+		fn on_runtime_upgrade() -> Weight {
+			#[cfg(feature = "try-runtime")]
+			migrations::pre_upgrade::<T>().expect("pre-upgrade checks failed");
+			let weight = migrations::migrate::<T>();
+			#[cfg(feature = "try-runtime")]
+			migrations::post_upgrade::<T>().expect("post-upgrade checks failed");
+			weight
 		}
 
 		// TODO: this on seems to be called only once
-		fn on_finalize(_n: T::BlockNumber) {}
+		fn on_finalize(_n: T::BlockNumber) {
+			Self::collect_vault_rewards();
+			let fees = Self::block_fees();
+			if fees != FeeDistribution::default() {
+				Self::deposit_event(Event::FeesDistributed(fees));
+			}
+		}
 	}
 
 	#[pallet::call]
@@ -241,6 +1167,7 @@ pub mod module {
 		/// - `initial_im_ratio`: Initial ratio needed for IM.
 		/// - `liquidation_ratio`: Minimum ratio needed for liquidation.
 		/// - `transaction_fee`: Transaction fee ratio taken by treasury.
+		/// - `brackets`: Ratios of larger positions, increasing with size.
 		/// TODO: add weights for this
 		#[pallet::weight((10_000, DispatchClass::Operational))]
 		#[transactional]
@@ -250,10 +1177,11 @@ pub mod module {
 			initial_im_ratio: ChangePermill,
 			liquidation_ratio: ChangePermill,
 			transaction_fee: ChangePermill,
+			brackets: ChangeBrackets,
 		) -> DispatchResultWithPostInfo {
 			T::UpdateOrigin::ensure_origin(origin)?;
 
-			ensure!(T::AssetIds::get().contains(&currency_id), Error::<T>::BadAssetID);
+			ensure!(Self::listed_assets().contains(&currency_id), Error::<T>::BadAssetID);
 
 			let mut collateral_params = Self::collateral_params(currency_id);
 
@@ -277,248 +1205,2530 @@ pub mod module {
 				Self::deposit_event(Event::TransactionFeeUpdated(currency_id, update));
 			}
 
+			if let Change::NewValue(update) = brackets {
+				collateral_params.brackets = update.clone();
+				Self::deposit_event(Event::RiskBracketsUpdated(currency_id, update));
+			}
+			ensure!(collateral_params.valid_brackets(), Error::<T>::BadIMParameters);
+
 			CollateralParams::<T>::insert(currency_id, collateral_params);
 
 			Ok(().into())
 		}
 
-		#[pallet::weight(<T as Config>::WeightInfo::mint_or_burn())]
+		/// List a new asset so it can be traded
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset to list.
+		/// - `params`: The risk management parameters of the asset.
+		#[pallet::weight((<T as Config>::WeightInfo::list_asset(), DispatchClass::Operational))]
 		#[transactional]
-		/// Mints the payoff
-		/// - `origin`: the calling account
-		/// - 'currency_id': The currency in use
-		/// - `amount`: the amount of asset to be minted(can be positive or negative)
-		/// - `collateral`: the amount of collateral in native currency
-		pub(super) fn mint(
+		pub(super) fn list_asset(
 			origin: OriginFor<T>,
 			currency_id: T::AssetId,
-			amount: i128,
-			collateral: i128,
+			params: AssetParams,
 		) -> DispatchResultWithPostInfo {
-			let who = ensure_signed(origin)?;
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(
+				!Self::listed_assets().contains(&currency_id),
+				Error::<T>::AssetAlreadyListed
+			);
+			ensure!(
+				params.liquidation_ratio < params.initial_im_ratio && params.valid_brackets(),
+				Error::<T>::BadIMParameters
+			);
+
+			CollateralParams::<T>::insert(currency_id, params.clone());
+			ListedAssets::<T>::append(currency_id);
+			Self::deposit_event(Event::AssetListed(currency_id, params));
 
-			let transaction_fee = Self::collateral_params(currency_id).transaction_fee;
-	
-			// Check if enough collateral
-			let current_margin = Self::amount_try_from_balance(Margin::<T>::try_get(who.clone()).unwrap_or(0u128.into()))?;
-			let price = Self::price0(currency_id).ok_or(Error::<T>::PriceNotSet)?;
-			//TODO: very ugly
-			let pos_amount = Self::balance_try_from_amount_abs(amount)?;
-			let fee = price
-				.checked_mul_int(pos_amount)
-				.and_then(|res| Some(transaction_fee.mul_ceil(res)))
-				.ok_or(Error::<T>::Overflow)?;
-			let f = Self::amount_try_from_balance(fee)?;
-	
-			let new_collateral = collateral.checked_sub(f).ok_or(Error::<T>::Overflow)?;
-	
-			let (needed_im, balance) = Self::get_needed_im(&who, &currency_id, amount)?;
-			let new_margin = current_margin.checked_add(new_collateral).ok_or(Error::<T>::Overflow)?;
-	
-			ensure!(new_margin >= needed_im, Error::<T>::NotEnoughIM);
-	
-			let module_account = Self::account_id();
-			let positive_margin = Self::balance_try_from_amount_abs(new_margin)?;
-			let pos_collateral = Self::balance_try_from_amount_abs(new_collateral)?;
-			let positive_collateral = Self::balance_to_pallet_balance(pos_collateral)?;
-	
-			if new_collateral.is_positive() {
-				// Transfer the collateral to the module's account
-				T::Currency::transfer(
-					&who,
-					&module_account,
-					positive_collateral,
-					ExistenceRequirement::KeepAlive,
-				)?;
-			}
-	
-			if new_collateral.is_negative() {
-				// Transfer the collateral from the module's account
-				T::Currency::transfer(
-					&module_account,
-					&who,
-					positive_collateral,
-					ExistenceRequirement::KeepAlive,
-				)?;
-			}
-	
-			// transfer the fee
-			let fee_balance = Self::balance_to_pallet_balance(fee)?;
-			let imbalance =
-				T::Currency::withdraw(&who, fee_balance, WithdrawReasons::FEE, ExistenceRequirement::KeepAlive)?;
-	
-			T::Treasury::on_unbalanced(imbalance);
-	
-			if !new_collateral.is_zero() {
-				Margin::<T>::insert(who.clone(), positive_margin);
-				Self::deposit_event(Event::CollateralUpdated(new_collateral));
-			}
-	
-			// Update the balances
-			Balances::<T>::insert(currency_id, who.clone(), balance);
-			Self::deposit_event(Event::BalanceUpdated(who, balance));
-	
 			Ok(().into())
 		}
-	}
-}
 
-impl<T: Config> Pallet<T> {
-	fn get_needed_im(
-		account: &T::AccountId,
-		currency_id: &T::AssetId,
-		amount: i128,
-	) -> result::Result<(i128, i128), Error<T>> {
-		let mut total_im_needed: u128 = 0u128;
-		let mut amt = 0;
-		ensure!(T::AssetIds::get().contains(currency_id), Error::<T>::BadAssetID);
-		for ccy_id in T::AssetIds::get() {
-			let price = Self::price0(ccy_id).ok_or(Error::<T>::PriceNotSet)?;
-			let initial_im_ratio = Self::collateral_params(ccy_id).initial_im_ratio;
-			let mut balance = Balances::<T>::try_get(ccy_id, account.clone()).unwrap_or(0.into());
-			if ccy_id == *currency_id {
-				balance += amount;
-				amt = balance;
-			}
-			let value = price.checked_mul_int(balance).ok_or(Error::<T>::Overflow)?;
-			let value = Self::balance_try_from_amount_abs(value)?;
-			total_im_needed += initial_im_ratio.mul_ceil(value);
-		}
-		let res = Self::amount_try_from_balance(total_im_needed)?;
-		Ok((res, amt))
-	}
+		/// Set the volume based fee tiers
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// Accounts below the first tier pay the asset's `transaction_fee`.
+		///
+		/// - `tiers`: The fee tiers, sorted by strictly increasing `min_volume`.
+		#[pallet::weight((<T as Config>::WeightInfo::set_fee_tiers(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_fee_tiers(origin: OriginFor<T>, tiers: Vec<FeeTier>) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
 
-	/// Call *M* the total margin for a participant *A*,
-	/// Call $T_i$ the total interest in asset *i*, and $B_i$ the inventory
-	/// (open interest is $T_i - B_i$) The needed collateral for maintaining
-	/// the inventory is $\sum_i B_i * P_i * L_i$. If $`sum_i B_i * P_i * L_i >= M$,
-	/// then liquididate the inventory as per below.
-	/// If $\sum_i B_i * P_i * L_i < M$, but $\sum_i T_i * P_i * L_i > M$ then close out
-	/// all open interest, so total interest becomes $\forall i, T_i = B_i$
-	/// and inventory remains at $B_i$
-	/// This is done to make sure that if an opposing open interest comes during
-	/// that block, it does not suffer from immediate liquidation.
-	///
-	/// ### Liquidation of inventory
-	/// If $\sum_i B_i * P_i * L_i >= M$, liquidate all the positions
-	/// so total position and inventory goes to $\forall i, T_i = B_i = 0$
-	fn liquidate() {
-		for (account, margin) in Margin::<T>::iter() {
-			let mut liquidation_sum = 0;
-			let mut unwind_sum = 0;
-			for currency_id in T::AssetIds::get() {
-				//TODO handle no price better
-				if let Some(price) = Self::price0(currency_id) {
-					let liq_div = Self::collateral_params(currency_id).liquidation_ratio;
-
-					// TODO handle overflow better (for example emergency shutdown) for the 2 statements below
-					let inventory =
-						Self::balance_try_from_amount_abs(Self::inventory(currency_id, account.clone())).unwrap();
-					let balance =
-						Self::balance_try_from_amount_abs(Balances::<T>::get(currency_id, account.clone())).unwrap();
-
-					//TODO: replace the saturating mul by a checked one
-					liquidation_sum += liq_div.mul_ceil(price.saturating_mul_int(inventory));
-					unwind_sum += liq_div.mul_ceil(price.saturating_mul_int(balance));
-				}
-			}
+			ensure!(
+				tiers.windows(2).all(|w| w[0].min_volume < w[1].min_volume),
+				Error::<T>::BadFeeTiers
+			);
 
-			// am I in liquidation?
-			if liquidation_sum >= margin {
-				// Yes I am
-				for currency_id in T::AssetIds::get() {
-					Balances::<T>::insert(currency_id, account.clone(), 0);
-					Inventory::<T>::insert(currency_id, account.clone(), 0);
+			FeeTiers::<T>::put(tiers.clone());
+			Self::deposit_event(Event::FeeTiersUpdated(tiers));
+
+			Ok(().into())
+		}
+
+		/// Set how fees are split between the treasury, the insurance fund
+		/// and the liquidity providers' reward pot
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `insurance`: Share of the fees sent to the insurance fund.
+		/// - `rewards`: Share of the fees sent to the reward pot.
+		#[pallet::weight((<T as Config>::WeightInfo::set_fee_split(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_fee_split(
+			origin: OriginFor<T>,
+			insurance: Permill,
+			rewards: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(
+				insurance.deconstruct().saturating_add(rewards.deconstruct()) <= Permill::one().deconstruct(),
+				Error::<T>::BadFeeSplit
+			);
+
+			let split = FeeSplit { insurance, rewards };
+			FeeSplitParams::<T>::put(split.clone());
+			Self::deposit_event(Event::FeeSplitUpdated(split));
+
+			Ok(().into())
+		}
+
+		/// Set the share of fees paid to referrers and rebated to referred
+		/// traders
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `referrer_share`: Share of the fees accruing to the referrer.
+		/// - `trader_rebate`: Share of the fees rebated to the trader.
+		#[pallet::weight((<T as Config>::WeightInfo::set_referral_params(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_referral_params(
+			origin: OriginFor<T>,
+			referrer_share: Permill,
+			trader_rebate: Permill,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(
+				referrer_share.deconstruct().saturating_add(trader_rebate.deconstruct())
+					<= Permill::one().deconstruct(),
+				Error::<T>::BadReferralParams
+			);
+
+			let params = ReferralParams {
+				referrer_share,
+				trader_rebate,
+			};
+			ReferralParameters::<T>::put(params.clone());
+			Self::deposit_event(Event::ReferralParamsUpdated(params));
+
+			Ok(().into())
+		}
+
+		/// Register the referrer of the calling account, can only be done once
+		///
+		/// - `origin`: the calling account
+		/// - `referrer`: the referring account
+		#[pallet::weight(<T as Config>::WeightInfo::register_referrer())]
+		#[transactional]
+		pub(super) fn register_referrer(origin: OriginFor<T>, referrer: T::AccountId) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			ensure!(!Referrers::<T>::contains_key(&who), Error::<T>::ReferrerAlreadySet);
+			ensure!(referrer != who, Error::<T>::SelfReferral);
+			// Prevent two accounts from referring each other
+			ensure!(
+				Self::referrer_of(&referrer).as_ref() != Some(&who),
+				Error::<T>::SelfReferral
+			);
+
+			Referrers::<T>::insert(&who, &referrer);
+			Self::deposit_event(Event::ReferrerRegistered(who, referrer));
+
+			Ok(().into())
+		}
+
+		/// Claim the referral rewards and rebates of the calling account
+		///
+		/// - `origin`: the calling account
+		#[pallet::weight(<T as Config>::WeightInfo::claim_rewards())]
+		#[transactional]
+		pub(super) fn claim_rewards(origin: OriginFor<T>) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let amount = PendingRewards::<T>::take(&who);
+			ensure!(!amount.is_zero(), Error::<T>::NoRewards);
+
+			T::Currency::transfer(
+				&Self::referral_pot_account(),
+				&who,
+				Self::balance_to_pallet_balance(amount)?,
+				ExistenceRequirement::AllowDeath,
+			)?;
+			Self::deposit_event(Event::RewardsClaimed(who, amount));
+
+			Ok(().into())
+		}
+
+		/// Set how the oversubscribed side of an asset is filled
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset.
+		/// - `policy`: The matching policy.
+		#[pallet::weight((<T as Config>::WeightInfo::set_matching_policy(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_matching_policy(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			policy: MatchingPolicy,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(Self::listed_assets().contains(&currency_id), Error::<T>::BadAssetID);
+
+			CollateralParams::<T>::mutate(currency_id, |params| params.matching_policy = policy);
+			Self::deposit_event(Event::MatchingPolicyUpdated(currency_id, policy));
+
+			Ok(().into())
+		}
+
+		/// Set the groups of assets whose opposite positions offset each
+		/// other's margin requirement. An empty list margins every asset on
+		/// its own.
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `groups`: The offset groups.
+		#[pallet::weight((<T as Config>::WeightInfo::set_offset_groups(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_offset_groups(
+			origin: OriginFor<T>,
+			groups: Vec<OffsetGroup<T::AssetId>>,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			let assets = Self::listed_assets();
+			let mut grouped: Vec<T::AssetId> = Vec::new();
+			for group in groups.iter() {
+				ensure!(group.assets.len() >= 2, Error::<T>::InvalidOffsetGroup);
+				for currency_id in group.assets.iter() {
+					ensure!(
+						assets.contains(currency_id) && !grouped.contains(currency_id),
+						Error::<T>::InvalidOffsetGroup
+					);
+					grouped.push(*currency_id);
+				}
+			}
+
+			OffsetGroups::<T>::put(groups.clone());
+			Self::deposit_event(Event::OffsetGroupsUpdated(groups));
+
+			Ok(().into())
+		}
+
+		/// Cap the caller's leverage in an asset below the one allowed by its
+		/// IM ratio. Only new trades are checked against it.
+		///
+		/// - `currency_id`: The asset.
+		/// - `leverage`: The maximum leverage, at least 1, or `None` to use the
+		///   asset's.
+		#[pallet::weight(<T as Config>::WeightInfo::set_leverage())]
+		#[transactional]
+		pub(super) fn set_leverage(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			leverage: Option<FixedU128>,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			ensure!(Self::listed_assets().contains(&currency_id), Error::<T>::BadAssetID);
+
+			match leverage {
+				Some(leverage) => {
+					ensure!(leverage >= FixedU128::one(), Error::<T>::InvalidLeverage);
+					Leverages::<T>::insert(currency_id, &who, leverage);
+				}
+				None => Leverages::<T>::remove(currency_id, &who),
+			}
+			Self::deposit_event(Event::LeverageUpdated(who, currency_id, leverage));
+
+			Ok(().into())
+		}
+
+		/// Place an order trading `amount` of an asset once its price is on
+		/// the `direction` side of `trigger_price`. The order fires once,
+		/// whether its trade succeeds or not.
+		///
+		/// - `currency_id`: The asset.
+		/// - `trigger_price`: The price at which the order fires.
+		/// - `direction`: Whether the order fires at or above, or at or below
+		///   the trigger price.
+		/// - `amount`: The amount to trade, with no additional collateral.
+		/// - `reduce_only`: Only reduce the position, capping `amount` to it.
+		/// - `on_behalf_of`: The account to place the order for, if the caller
+		///   is its delegate.
+		#[pallet::weight(<T as Config>::WeightInfo::place_trigger_order())]
+		#[transactional]
+		pub(super) fn place_trigger_order(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			trigger_price: FixedU128,
+			direction: TriggerDirection,
+			amount: i128,
+			reduce_only: bool,
+			on_behalf_of: Option<T::AccountId>,
+		) -> DispatchResultWithPostInfo {
+			let signer = ensure_signed(origin)?;
+			let who = Self::ensure_delegated(signer, on_behalf_of, currency_id, &[DelegateAction::Trade])?;
+
+			ensure!(Self::listed_assets().contains(&currency_id), Error::<T>::BadAssetID);
+			ensure!(!Self::is_expired(currency_id), Error::<T>::MarketExpired);
+			ensure!(
+				amount != 0 && !trigger_price.is_zero(),
+				Error::<T>::InvalidTriggerOrder
+			);
+			let count = Self::trigger_order_count(&who);
+			ensure!(count < T::MaxTriggerOrders::get(), Error::<T>::TooManyTriggerOrders);
+
+			let order_id = NextOrderId::<T>::get();
+			NextOrderId::<T>::put(order_id.checked_add(1).ok_or(Error::<T>::Overflow)?);
+			let deposit = T::TriggerOrderDeposit::get();
+			T::Currency::reserve(&who, deposit)?;
+			TriggerOrders::<T>::insert(
+				currency_id,
+				order_id,
+				TriggerOrder {
+					account: who.clone(),
+					trigger_price,
+					direction,
+					amount,
+					reduce_only,
+					deposit,
+				},
+			);
+			TriggerOrderCount::<T>::insert(&who, count + 1);
+			Self::deposit_event(Event::TriggerOrderPlaced(who, currency_id, order_id));
+
+			Ok(().into())
+		}
+
+		/// Cancel an open trigger order of the caller
+		///
+		/// - `currency_id`: The asset.
+		/// - `order_id`: The order.
+		/// - `on_behalf_of`: The account that placed the order, if the caller
+		///   is its delegate.
+		#[pallet::weight(<T as Config>::WeightInfo::cancel_trigger_order())]
+		#[transactional]
+		pub(super) fn cancel_trigger_order(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			order_id: OrderId,
+			on_behalf_of: Option<T::AccountId>,
+		) -> DispatchResultWithPostInfo {
+			let signer = ensure_signed(origin)?;
+			let who = Self::ensure_delegated(signer, on_behalf_of, currency_id, &[DelegateAction::Trade])?;
+
+			let order = Self::trigger_order(currency_id, order_id)
+				.filter(|order| order.account == who)
+				.ok_or(Error::<T>::TriggerOrderNotFound)?;
+
+			Self::remove_trigger_order(currency_id, order_id, &order);
+			Self::deposit_event(Event::TriggerOrderCancelled(who, currency_id, order_id));
+
+			Ok(().into())
+		}
+
+		/// Move part of the caller's position to another account with the
+		/// share of its margin the position needs, without trading. Both
+		/// accounts must hold their IM afterwards, and the receiving account
+		/// must have approved the transfer with `approve_position_transfer`.
+		///
+		/// - `currency_id`: The asset.
+		/// - `to`: The receiving account.
+		/// - `amount`: The amount to move, on the side of the position.
+		#[pallet::weight(<T as Config>::WeightInfo::transfer_position())]
+		#[transactional]
+		pub(super) fn transfer_position(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			to: T::AccountId,
+			amount: i128,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+			ensure!(!Self::is_pallet_account(&to), Error::<T>::InvalidTransfer);
+
+			let moved = Self::do_transfer_position(&who, currency_id, &to, amount)?;
+			Self::limit_margin_transfer(&who, moved)?;
+			PositionApprovals::<T>::try_mutate_exists(&to, &who, |approval| -> DispatchResult {
+				let (asset, approved) = (*approval).ok_or(Error::<T>::TransferNotApproved)?;
+				ensure!(
+					asset == currency_id
+						&& (amount > 0) == (approved > 0)
+						&& amount.saturating_abs() <= approved.saturating_abs(),
+					Error::<T>::TransferNotApproved
+				);
+				let left = approved - amount;
+				*approval = if left == 0 { None } else { Some((asset, left)) };
+				Ok(())
+			})?;
+			Self::deposit_event(Event::PositionTransferred(who, currency_id, amount, to, moved));
+
+			Ok(().into())
+		}
+
+		/// Accept to receive up to `amount` of a position in `currency_id`
+		/// from `from`, replacing any previous approval for it. Receiving a
+		/// position moves its risk and reserves a storage deposit, so
+		/// `transfer_position` needs this first.
+		///
+		/// - `from`: The sending account.
+		/// - `currency_id`: The asset.
+		/// - `amount`: The largest amount, on the side of the position, or
+		///   zero to remove the approval.
+		#[pallet::weight(<T as Config>::WeightInfo::approve_position_transfer())]
+		#[transactional]
+		pub(super) fn approve_position_transfer(
+			origin: OriginFor<T>,
+			from: T::AccountId,
+			currency_id: T::AssetId,
+			amount: i128,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			if amount == 0 {
+				PositionApprovals::<T>::remove(&who, &from);
+			} else {
+				PositionApprovals::<T>::insert(&who, &from, (currency_id, amount));
+			}
+			Self::deposit_event(Event::PositionTransferApproved(who, from, currency_id, amount));
+
+			Ok(().into())
+		}
+
+		/// Wrap part of the caller's position into a token holding it in
+		/// isolation, with the share of margin it needs. The position stays
+		/// marked and liquidated like any other. The token can only be
+		/// moved with `transfer_position_token`.
+		///
+		/// - `currency_id`: The asset.
+		/// - `amount`: The amount to wrap, on the side of the position.
+		#[pallet::weight(<T as Config>::WeightInfo::wrap_position())]
+		#[transactional]
+		pub(super) fn wrap_position(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			amount: i128,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let token_id = NextPositionTokenId::<T>::get();
+			NextPositionTokenId::<T>::put(token_id.checked_add(1).ok_or(Error::<T>::Overflow)?);
+			let token_account = Self::position_token_account(token_id);
+
+			// The owner pays the storage deposit of the token's account
+			let deposit = T::StorageDeposit::get();
+			if !deposit.is_zero() {
+				T::Currency::transfer(&who, &token_account, deposit, ExistenceRequirement::KeepAlive)?;
+			}
+			Self::do_transfer_position(&who, currency_id, &token_account, amount)?;
+
+			PositionTokens::<T>::insert(
+				token_id,
+				PositionToken {
+					owner: who.clone(),
+					asset: currency_id,
+				},
+			);
+			Self::deposit_event(Event::PositionWrapped(who, token_id, currency_id, amount));
+
+			Ok(().into())
+		}
+
+		/// Give a position token to another account
+		///
+		/// - `token_id`: The token.
+		/// - `to`: The new owner.
+		#[pallet::weight(<T as Config>::WeightInfo::transfer_position_token())]
+		#[transactional]
+		pub(super) fn transfer_position_token(
+			origin: OriginFor<T>,
+			token_id: PositionTokenId,
+			to: T::AccountId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			Self::do_transfer_position_token(token_id, &who, &to)?;
+
+			Ok(().into())
+		}
+
+		/// Burn a position token, restoring its position and margin to the
+		/// caller. The caller must hold the IM of the position afterwards.
+		///
+		/// - `token_id`: The token.
+		#[pallet::weight(<T as Config>::WeightInfo::unwrap_position())]
+		#[transactional]
+		pub(super) fn unwrap_position(origin: OriginFor<T>, token_id: PositionTokenId) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let token = Self::position_token(token_id).ok_or(Error::<T>::PositionTokenNotFound)?;
+			ensure!(token.owner == who, Error::<T>::NotTokenOwner);
+			let token_account = Self::position_token_account(token_id);
+
+			let balance = Self::balances(token.asset, &token_account);
+			if balance != 0 {
+				Self::do_transfer_position(&token_account, token.asset, &who, balance)?;
+			}
+			// Margin left once the position is gone, e.g. after a liquidation
+			let margin = Self::margin(&token_account);
+			if !margin.is_zero() {
+				Self::set_margin(&who, Self::margin(&who).checked_add(margin).ok_or(Error::<T>::Overflow)?)?;
+			}
+			Self::close_account(&token_account);
+			let free = T::Currency::free_balance(&token_account);
+			T::Currency::transfer(&token_account, &who, free, ExistenceRequirement::AllowDeath)?;
+
+			PositionTokens::<T>::remove(token_id);
+			Self::deposit_event(Event::PositionUnwrapped(who, token_id));
+
+			Ok(().into())
+		}
+
+		/// Authorise `delegate` to act for the caller, replacing any previous
+		/// delegation to it
+		///
+		/// - `delegate`: The delegate.
+		/// - `delegation`: The actions and assets allowed, and until when.
+		#[pallet::weight(<T as Config>::WeightInfo::set_delegation())]
+		#[transactional]
+		pub(super) fn set_delegation(
+			origin: OriginFor<T>,
+			delegate: T::AccountId,
+			delegation: Delegation<T::AssetId, T::BlockNumber>,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			Delegations::<T>::insert(&who, &delegate, delegation.clone());
+			Self::deposit_event(Event::DelegationUpdated(who, delegate, delegation));
+
+			Ok(().into())
+		}
+
+		/// Revoke a delegate of the caller
+		///
+		/// - `delegate`: The delegate.
+		#[pallet::weight(<T as Config>::WeightInfo::revoke_delegation())]
+		#[transactional]
+		pub(super) fn revoke_delegation(origin: OriginFor<T>, delegate: T::AccountId) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			ensure!(
+				Delegations::<T>::contains_key(&who, &delegate),
+				Error::<T>::NotDelegated
+			);
+			Delegations::<T>::remove(&who, &delegate);
+			Self::deposit_event(Event::DelegationRevoked(who, delegate));
+
+			Ok(().into())
+		}
+
+		/// Set or remove the limit on withdrawals from the caller's margin.
+		/// Withdrawals within the cap go through right away, larger ones are
+		/// queued for the timelock. A stricter limit applies right away, a
+		/// looser one or its removal only after the current timelock, or a
+		/// withdrawal period if it has none.
+		///
+		/// - `limit`: The new limit, or none to remove it.
+		#[pallet::weight(<T as Config>::WeightInfo::set_withdrawal_limit())]
+		#[transactional]
+		pub(super) fn set_withdrawal_limit(
+			origin: OriginFor<T>,
+			limit: Option<WithdrawalLimit<T::BlockNumber>>,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let current = match Self::active_withdrawal_limit(&who) {
+				Some(current) if limit.as_ref().map_or(true, |limit| !limit.is_stricter(&current)) => current,
+				_ => {
+					PendingWithdrawalLimits::<T>::remove(&who);
+					WithdrawalLimits::<T>::set(&who, limit.clone());
+					Self::deposit_event(Event::WithdrawalLimitUpdated(who, limit));
+					return Ok(().into());
+				}
+			};
+			let delay = current.timelock.unwrap_or_else(T::WithdrawalPeriod::get);
+			let apply_at = frame_system::Module::<T>::block_number().saturating_add(delay);
+			PendingWithdrawalLimits::<T>::insert(&who, (limit.clone(), apply_at));
+			Self::deposit_event(Event::WithdrawalLimitChangeQueued(who, limit, apply_at));
+
+			Ok(().into())
+		}
+
+		/// Execute a queued withdrawal of the caller whose timelock is over.
+		/// The margin left must still cover the IM. Only the margin is
+		/// touched, so this works whatever the state of the markets.
+		///
+		/// - `withdrawal_id`: The withdrawal.
+		#[pallet::weight(<T as Config>::WeightInfo::execute_withdrawal())]
+		#[transactional]
+		pub(super) fn execute_withdrawal(
+			origin: OriginFor<T>,
+			withdrawal_id: WithdrawalId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let withdrawal = Self::pending_withdrawal(&who, withdrawal_id).ok_or(Error::<T>::WithdrawalNotFound)?;
+			ensure!(
+				frame_system::Module::<T>::block_number() >= withdrawal.unlock_at,
+				Error::<T>::WithdrawalLocked
+			);
+
+			Self::remove_pending_withdrawal(&who, withdrawal_id);
+			Self::withdraw_margin(&who, withdrawal.amount)?;
+			Self::deposit_event(Event::WithdrawalExecuted(who, withdrawal_id, withdrawal.amount));
+
+			Ok(().into())
+		}
+
+		/// Cancel a queued withdrawal of the caller
+		///
+		/// - `withdrawal_id`: The withdrawal.
+		#[pallet::weight(<T as Config>::WeightInfo::cancel_withdrawal())]
+		#[transactional]
+		pub(super) fn cancel_withdrawal(
+			origin: OriginFor<T>,
+			withdrawal_id: WithdrawalId,
+		) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			ensure!(
+				PendingWithdrawals::<T>::contains_key(&who, withdrawal_id),
+				Error::<T>::WithdrawalNotFound
+			);
+			Self::remove_pending_withdrawal(&who, withdrawal_id);
+			Self::deposit_event(Event::WithdrawalCancelled(who, withdrawal_id));
+
+			Ok(().into())
+		}
+
+		/// Resume a market halted because its interest could not be matched
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset.
+		#[pallet::weight((<T as Config>::WeightInfo::resume_market(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn resume_market(origin: OriginFor<T>, currency_id: T::AssetId) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(Self::market_halted(currency_id), Error::<T>::MarketNotHalted);
+
+			HaltedMarkets::<T>::remove(currency_id);
+			Self::deposit_event(Event::MarketResumed(currency_id));
+
+			Ok(().into())
+		}
+
+		/// Release an account quarantined because its margin could not be
+		/// computed, marking its inventories from the prices they were last
+		/// marked at
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `account`: The account.
+		#[pallet::weight((<T as Config>::WeightInfo::release_account(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn release_account(origin: OriginFor<T>, account: T::AccountId) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(Self::quarantined(&account), Error::<T>::AccountNotQuarantined);
+
+			// Catch up with the prices the other accounts were marked at
+			for currency_id in Self::listed_assets() {
+				Self::mark_quarantined(&account, currency_id).ok_or(Error::<T>::Overflow)?;
+			}
+			QuarantinePrices::<T>::remove_prefix(&account);
+			Quarantined::<T>::remove(&account);
+			Self::deposit_event(Event::AccountReleased(account));
+
+			Ok(().into())
+		}
+
+		/// Make an asset a dated future expiring at `expiry`, or perpetual
+		/// again if `None`. At expiry all its positions are settled at the
+		/// average price over the last `SettlementWindow` blocks.
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset.
+		/// - `expiry`: The block at which the asset expires.
+		/// - `roll_into`: The asset positions can be rolled into before expiry.
+		#[pallet::weight((<T as Config>::WeightInfo::set_expiry(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_expiry(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			expiry: Option<T::BlockNumber>,
+			roll_into: Option<T::AssetId>,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			let assets = Self::listed_assets();
+			ensure!(assets.contains(&currency_id), Error::<T>::BadAssetID);
+			ensure!(!Self::is_expired(currency_id), Error::<T>::MarketExpired);
+			if let Some(target) = roll_into {
+				ensure!(
+					target != currency_id && assets.contains(&target),
+					Error::<T>::BadAssetID
+				);
+			}
+
+			SettlementSamples::<T>::remove(currency_id);
+			match expiry {
+				Some(expiry) => {
+					ensure!(
+						expiry > frame_system::Module::<T>::block_number(),
+						Error::<T>::InvalidExpiry
+					);
+					Expiries::<T>::insert(currency_id, (expiry, roll_into));
+				}
+				None => Expiries::<T>::remove(currency_id),
+			}
+			Self::deposit_event(Event::ExpiryUpdated(currency_id, expiry, roll_into));
+
+			Ok(().into())
+		}
+
+		/// Move the caller's position in a dated future into the market it
+		/// rolls into, paying the fees of both trades
+		///
+		/// - `currency_id`: The dated future.
+		#[pallet::weight(<T as Config>::WeightInfo::roll_position())]
+		#[transactional]
+		pub(super) fn roll_position(origin: OriginFor<T>, currency_id: T::AssetId) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin.clone())?;
+
+			let target = Self::expiry(currency_id)
+				.and_then(|(_, target)| target)
+				.ok_or(Error::<T>::NoRollTarget)?;
+			let balance = Self::balances(currency_id, &who);
+			ensure!(balance != 0, Error::<T>::NothingToRoll);
+			let amount = balance.checked_neg().ok_or(Error::<T>::Overflow)?;
+
+			Self::mint(origin.clone(), currency_id, amount, 0, None)?;
+			Self::mint(origin, target, balance, 0, None)?;
+			Self::deposit_event(Event::PositionRolled(who, balance, currency_id, target));
+
+			Ok(().into())
+		}
+
+		/// Set the maximum notional the vault can take in an asset
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset.
+		/// - `limit`: The maximum notional of the vault's position.
+		#[pallet::weight((<T as Config>::WeightInfo::set_vault_risk_limit(), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn set_vault_risk_limit(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			limit: u128,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			ensure!(Self::listed_assets().contains(&currency_id), Error::<T>::BadAssetID);
+
+			CollateralParams::<T>::mutate(currency_id, |params| params.vault_risk_limit = limit);
+			Self::deposit_event(Event::VaultRiskLimitUpdated(currency_id, limit));
+
+			Ok(().into())
+		}
+
+		/// Deposit collateral into the liquidity providers' vault
+		///
+		/// The vault takes the other side of any imbalance between longs and
+		/// shorts, and earns the reward share of the fees.
+		///
+		/// - `origin`: the calling account
+		/// - `amount`: the amount of collateral in native currency
+		#[pallet::weight(<T as Config>::WeightInfo::deposit_to_vault())]
+		#[transactional]
+		pub(super) fn deposit_to_vault(origin: OriginFor<T>, amount: u128) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let vault = Self::vault_account();
+			let vault_margin = Self::margin(&vault);
+			let total_shares = Self::total_vault_shares();
+			let shares = if total_shares.is_zero() {
+				amount
+			} else {
+				ensure!(!vault_margin.is_zero(), Error::<T>::VaultInsolvent);
+				multiply_by_rational(amount, total_shares, vault_margin).map_err(|_| Error::<T>::Overflow)?
+			};
+
+			T::Currency::transfer(
+				&who,
+				&Self::account_id(),
+				Self::balance_to_pallet_balance(amount)?,
+				ExistenceRequirement::KeepAlive,
+			)?;
+
+			Margin::<T>::insert(&vault, vault_margin.checked_add(amount).ok_or(Error::<T>::Overflow)?);
+			VaultShares::<T>::mutate(&who, |s| *s = s.saturating_add(shares));
+			TotalVaultShares::<T>::put(total_shares.checked_add(shares).ok_or(Error::<T>::Overflow)?);
+			Self::deposit_event(Event::VaultDeposited(who, amount, shares));
+
+			Ok(().into())
+		}
+
+		/// Request to withdraw shares from the vault, they can be withdrawn
+		/// after `VaultCooldown` blocks. Replaces any pending request.
+		///
+		/// - `origin`: the calling account
+		/// - `shares`: the number of shares to redeem
+		#[pallet::weight(<T as Config>::WeightInfo::request_vault_withdrawal())]
+		#[transactional]
+		pub(super) fn request_vault_withdrawal(origin: OriginFor<T>, shares: u128) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			ensure!(shares <= Self::vault_shares(&who), Error::<T>::NotEnoughShares);
+
+			let unlock_at = frame_system::Module::<T>::block_number() + T::VaultCooldown::get();
+			VaultWithdrawals::<T>::insert(&who, (shares, unlock_at));
+			Self::deposit_event(Event::VaultWithdrawalRequested(who, shares, unlock_at));
+
+			Ok(().into())
+		}
+
+		/// Withdraw the shares requested with `request_vault_withdrawal`
+		///
+		/// - `origin`: the calling account
+		#[pallet::weight(<T as Config>::WeightInfo::withdraw_from_vault())]
+		#[transactional]
+		pub(super) fn withdraw_from_vault(origin: OriginFor<T>) -> DispatchResultWithPostInfo {
+			let who = ensure_signed(origin)?;
+
+			let (shares, unlock_at) = VaultWithdrawals::<T>::take(&who).ok_or(Error::<T>::NoPendingWithdrawal)?;
+			ensure!(
+				frame_system::Module::<T>::block_number() >= unlock_at,
+				Error::<T>::VaultCooldownNotOver
+			);
+			let owned = Self::vault_shares(&who);
+			ensure!(shares <= owned, Error::<T>::NotEnoughShares);
+
+			Self::collect_vault_rewards();
+
+			let vault = Self::vault_account();
+			let vault_margin = Self::margin(&vault);
+			let total_shares = Self::total_vault_shares();
+			let amount = multiply_by_rational(shares, vault_margin, total_shares).map_err(|_| Error::<T>::Overflow)?;
+			let new_margin = vault_margin.saturating_sub(amount);
+
+			// The vault must still cover its positions
+			let needed_im = Self::initial_margin(&vault, None)?;
+			ensure!(
+				Self::amount_try_from_balance(new_margin)? >= needed_im,
+				Error::<T>::NotEnoughIM
+			);
+
+			T::Currency::transfer(
+				&Self::account_id(),
+				&who,
+				Self::balance_to_pallet_balance(amount)?,
+				ExistenceRequirement::KeepAlive,
+			)?;
+
+			Margin::<T>::insert(&vault, new_margin);
+			VaultShares::<T>::insert(&who, owned - shares);
+			TotalVaultShares::<T>::put(total_shares.saturating_sub(shares));
+			Self::deposit_event(Event::VaultWithdrawn(who, amount, shares));
+
+			Ok(().into())
+		}
+
+		/// Delist an asset, all open positions are settled at the last
+		/// known price
+		///
+		/// The dispatch origin of this call must be `UpdateOrigin`.
+		///
+		/// - `currency_id`: The asset to delist.
+		/// - `entries`: Upper bound on the number of account entries of the
+		///   asset, see `asset_entries`. The call is weighed on it, and
+		///   refunded down to the actual number.
+		#[pallet::weight((<T as Config>::WeightInfo::delist_asset(*entries), DispatchClass::Operational))]
+		#[transactional]
+		pub(super) fn delist_asset(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			entries: u32,
+		) -> DispatchResultWithPostInfo {
+			T::UpdateOrigin::ensure_origin(origin)?;
+
+			let mut assets = Self::listed_assets();
+			ensure!(assets.contains(&currency_id), Error::<T>::BadAssetID);
+			let actual_entries = Self::asset_entries(currency_id, entries);
+			ensure!(actual_entries <= entries, Error::<T>::TooManyEntries);
+
+			// Margin is already marked to market at Price0 by `update_margin`,
+			// so settling only requires closing the positions
+			Self::close_positions(currency_id);
+			Positions::<T>::remove_prefix(currency_id);
+			Price0::<T>::remove(currency_id);
+			HaltedMarkets::<T>::remove(currency_id);
+			Expiries::<T>::remove(currency_id);
+			SettlementSamples::<T>::remove(currency_id);
+			SettlementPrices::<T>::remove(currency_id);
+			CollateralParams::<T>::remove(currency_id);
+			Leverages::<T>::remove_prefix(currency_id);
+			OffsetGroups::<T>::mutate(|groups| {
+				for group in groups.iter_mut() {
+					group.assets.retain(|id| *id != currency_id);
+				}
+				groups.retain(|group| group.assets.len() >= 2);
+			});
+
+			assets.retain(|id| *id != currency_id);
+			ListedAssets::<T>::put(assets);
+			Self::deposit_event(Event::AssetDelisted(currency_id));
+
+			Ok(Some(<T as Config>::WeightInfo::delist_asset(actual_entries)).into())
+		}
+
+		#[pallet::weight(<T as Config>::WeightInfo::mint_or_burn())]
+		#[transactional]
+		/// Mints the payoff
+		/// - `origin`: the calling account
+		/// - 'currency_id': The currency in use
+		/// - `amount`: the amount of asset to be minted(can be positive or negative)
+		/// - `collateral`: the amount of collateral in native currency
+		/// - `on_behalf_of`: the account to trade for, if the caller is its
+		///   delegate. Collateral moves from and to that account.
+		pub(super) fn mint(
+			origin: OriginFor<T>,
+			currency_id: T::AssetId,
+			amount: i128,
+			collateral: i128,
+			on_behalf_of: Option<T::AccountId>,
+		) -> DispatchResultWithPostInfo {
+			let signer = ensure_signed(origin)?;
+			let actions: &[DelegateAction] = if collateral < 0 {
+				&[DelegateAction::Trade, DelegateAction::Withdraw]
+			} else {
+				&[DelegateAction::Trade]
+			};
+			let who = Self::ensure_delegated(signer, on_behalf_of, currency_id, actions)?;
+			let collateral = Self::limit_withdrawal(&who, collateral)?;
+			Self::do_mint(&who, currency_id, amount, collateral, true)?;
+
+			Ok(().into())
+		}
+
+		/// Trade several assets at once, the IM being only checked on the
+		/// final positions
+		///
+		/// - `legs`: The assets and amounts to trade, each asset at most once.
+		/// - `collateral`: The amount of collateral added, or withdrawn if
+		///   negative.
+		/// - `on_behalf_of`: The account to trade for, if the caller is its
+		///   delegate on every asset traded.
+		#[pallet::weight(<T as Config>::WeightInfo::batch_mint(legs.len() as u32))]
+		#[transactional]
+		pub(super) fn batch_mint(
+			origin: OriginFor<T>,
+			legs: Vec<(T::AssetId, i128)>,
+			collateral: i128,
+			on_behalf_of: Option<T::AccountId>,
+		) -> DispatchResultWithPostInfo {
+			let signer = ensure_signed(origin)?;
+			ensure!(!legs.is_empty(), Error::<T>::InvalidBatch);
+			let actions: &[DelegateAction] = if collateral < 0 {
+				&[DelegateAction::Trade, DelegateAction::Withdraw]
+			} else {
+				&[DelegateAction::Trade]
+			};
+			let mut who = signer.clone();
+			for (i, (currency_id, _)) in legs.iter().enumerate() {
+				ensure!(
+					legs[..i].iter().all(|(id, _)| id != currency_id),
+					Error::<T>::InvalidBatch
+				);
+				who = Self::ensure_delegated(signer.clone(), on_behalf_of.clone(), *currency_id, actions)?;
+			}
+
+			// The collateral moves with the first leg and the IM is checked
+			// with the last one, once all positions are updated
+			let collateral = Self::limit_withdrawal(&who, collateral)?;
+			let last = legs.len() - 1;
+			for (i, (currency_id, amount)) in legs.into_iter().enumerate() {
+				Self::do_mint(&who, currency_id, amount, if i == 0 { collateral } else { 0 }, i == last)?;
+			}
+
+			Ok(().into())
+		}
+	}
+}
+
+impl<T: Config> Pallet<T> {
+	/// Trade `amount` of `currency_id` for `who` and move `collateral` in or
+	/// out of its margin. The IM is only checked if `check_im`, otherwise the
+	/// margin only has to stay positive.
+	fn do_mint(
+		who: &T::AccountId,
+		currency_id: T::AssetId,
+		amount: i128,
+		collateral: i128,
+		check_im: bool,
+	) -> DispatchResult {
+		ensure!(!Self::market_halted(currency_id), Error::<T>::MarketIsHalted);
+		ensure!(!Self::is_expired(currency_id), Error::<T>::MarketExpired);
+		ensure!(!Self::quarantined(who), Error::<T>::AccountIsQuarantined);
+
+		let liquidity = Self::liquidity_of(currency_id, amount);
+		let fee_rate = Self::fee_rate(who, currency_id, liquidity);
+
+		// Check if enough collateral
+		let current_margin = Self::amount_try_from_balance(Margin::<T>::try_get(who).unwrap_or(0u128.into()))?;
+		let price = Self::price0(currency_id).ok_or(Error::<T>::PriceNotSet)?;
+		//TODO: very ugly
+		let pos_amount = Self::balance_try_from_amount_abs(amount)?;
+		let unit_value = Self::collateral_params(currency_id)
+			.payoff
+			.margin_unit_value(price)
+			.ok_or(Error::<T>::InvalidPrice)?;
+		let notional = unit_value.checked_mul_int(pos_amount).ok_or(Error::<T>::Overflow)?;
+		let fee = fee_rate.mul_ceil(notional);
+		let f = Self::amount_try_from_balance(fee)?;
+
+		let new_collateral = collateral.checked_sub(f).ok_or(Error::<T>::Overflow)?;
+
+		let (needed_im, balance) = Self::get_needed_im(who, &currency_id, amount)?;
+		let new_margin = current_margin.checked_add(new_collateral).ok_or(Error::<T>::Overflow)?;
+
+		ensure!(
+			new_margin >= if check_im { needed_im } else { 0 },
+			Error::<T>::NotEnoughIM
+		);
+
+		let module_account = Self::account_id();
+		let positive_margin = Self::balance_try_from_amount_abs(new_margin)?;
+		let pos_collateral = Self::balance_try_from_amount_abs(new_collateral)?;
+		let positive_collateral = Self::balance_to_pallet_balance(pos_collateral)?;
+
+		if new_collateral.is_positive() {
+			// Transfer the collateral to the module's account
+			T::Currency::transfer(
+				who,
+				&module_account,
+				positive_collateral,
+				ExistenceRequirement::KeepAlive,
+			)?;
+		}
+
+		if new_collateral.is_negative() {
+			// Transfer the collateral from the module's account
+			T::Currency::transfer(
+				&module_account,
+				who,
+				positive_collateral,
+				ExistenceRequirement::KeepAlive,
+			)?;
+		}
+
+		// transfer the fee
+		let fee_balance = Self::balance_to_pallet_balance(fee)?;
+		let imbalance =
+			T::Currency::withdraw(who, fee_balance, WithdrawReasons::FEE, ExistenceRequirement::KeepAlive)?;
+
+		Self::distribute_fee(who, imbalance, fee)?;
+
+		if amount != 0 {
+			Self::record_volume(who, notional);
+			Self::deposit_event(Event::Traded(TradePayload::V1 {
+				account: who.clone(),
+				asset: currency_id,
+				amount,
+				notional,
+				price,
+				fee,
+				rate: fee_rate,
+				liquidity,
+			}));
+		}
+
+		Self::update_order_block(currency_id, who, balance - amount, balance);
+
+		// Update the balances
+		Self::set_balance(currency_id, who, balance);
+
+		if !new_collateral.is_zero() || !Margin::<T>::contains_key(who) {
+			Self::set_margin(who, positive_margin)?;
+		}
+		if !new_collateral.is_zero() {
+			Self::deposit_event(Event::CollateralUpdated(
+				who.clone(),
+				currency_id,
+				new_collateral,
+				positive_margin,
+			));
+		}
+
+		Self::deposit_event(Event::BalanceUpdated(who.clone(), currency_id, balance));
+
+		Ok(())
+	}
+
+	/// Account `signer` acts for: itself, or `on_behalf_of` if it delegated
+	/// all of `actions` on `currency_id` to `signer` and the delegation has
+	/// not expired
+	fn ensure_delegated(
+		signer: T::AccountId,
+		on_behalf_of: Option<T::AccountId>,
+		currency_id: T::AssetId,
+		actions: &[DelegateAction],
+	) -> Result<T::AccountId, DispatchError> {
+		let owner = match on_behalf_of {
+			Some(owner) if owner != signer => owner,
+			_ => return Ok(signer),
+		};
+		let delegation = Self::delegation(&owner, &signer).ok_or(Error::<T>::NotDelegated)?;
+		let now = frame_system::Module::<T>::block_number();
+		ensure!(
+			actions.iter().all(|action| delegation.actions.contains(action))
+				&& (delegation.assets.is_empty() || delegation.assets.contains(&currency_id))
+				&& delegation.expiry.map_or(true, |expiry| now <= expiry),
+			Error::<T>::NotDelegated
+		);
+		Ok(owner)
+	}
+
+	/// Collateral to move now for `who`, given the `collateral` it asked
+	/// for. A withdrawal within its cap is counted against it, a larger one
+	/// is queued for the timelock and nothing is withdrawn now.
+	fn limit_withdrawal(who: &T::AccountId, collateral: i128) -> Result<i128, DispatchError> {
+		if collateral >= 0 {
+			return Ok(collateral);
+		}
+		let limit = match Self::active_withdrawal_limit(who) {
+			Some(limit) => limit,
+			None => return Ok(collateral),
+		};
+		let amount = Self::balance_try_from_amount_abs(collateral)?;
+		if Self::count_withdrawal(who, &limit, amount)? {
+			return Ok(collateral);
+		}
+
+		let now = frame_system::Module::<T>::block_number();
+		let timelock = limit.timelock.ok_or(Error::<T>::WithdrawalLimitExceeded)?;
+		let count = Self::pending_withdrawal_count(who);
+		ensure!(
+			count < T::MaxPendingWithdrawals::get(),
+			Error::<T>::TooManyPendingWithdrawals
+		);
+		let withdrawal_id = NextWithdrawalId::<T>::get();
+		NextWithdrawalId::<T>::put(withdrawal_id.checked_add(1).ok_or(Error::<T>::Overflow)?);
+		let unlock_at = now.saturating_add(timelock);
+		PendingWithdrawals::<T>::insert(
+			who,
+			withdrawal_id,
+			PendingWithdrawal { amount, unlock_at },
+		);
+		PendingWithdrawalCount::<T>::insert(who, count + 1);
+		Self::deposit_event(Event::WithdrawalQueued(who.clone(), withdrawal_id, amount, unlock_at));
+		Ok(0)
+	}
+
+	/// Count margin leaving `who` other than by a withdrawal, such as with a
+	/// position or a position token, against its withdrawal cap. This
+	/// cannot be queued, so it fails over the cap.
+	fn limit_margin_transfer(who: &T::AccountId, amount: u128) -> DispatchResult {
+		if amount.is_zero() {
+			return Ok(());
+		}
+		if let Some(limit) = Self::active_withdrawal_limit(who) {
+			ensure!(
+				Self::count_withdrawal(who, &limit, amount)?,
+				Error::<T>::WithdrawalLimitExceeded
+			);
+		}
+		Ok(())
+	}
+
+	/// Add `amount` to the collateral `who` withdrew over the last
+	/// withdrawal period if it stays within the cap of `limit`, returning
+	/// whether it did. What was withdrawn frees up the cap linearly over
+	/// the period, so it never resets all at once.
+	fn count_withdrawal(
+		who: &T::AccountId,
+		limit: &WithdrawalLimit<T::BlockNumber>,
+		amount: u128,
+	) -> Result<bool, DispatchError> {
+		let period: u128 = T::WithdrawalPeriod::get().unique_saturated_into();
+		if period == 0 {
+			return Ok(true);
+		}
+		let now = frame_system::Module::<T>::block_number();
+		let (last, withdrawn) = Self::withdrawn_collateral(who);
+		let elapsed: u128 = now.saturating_sub(last).unique_saturated_into();
+		let withdrawn = if elapsed >= period {
+			0
+		} else {
+			// Rounded down, against the account
+			let freed = multiply_by_rational(limit.cap, elapsed, period).map_err(|_| Error::<T>::Overflow)?;
+			withdrawn.saturating_sub(freed)
+		};
+		let total = withdrawn.checked_add(amount).ok_or(Error::<T>::Overflow)?;
+		if total > limit.cap {
+			return Ok(false);
+		}
+		WithdrawnCollateral::<T>::insert(who, (now, total));
+		Ok(true)
+	}
+
+	/// Withdrawal limit of `who`, after applying its looser limit if due
+	fn active_withdrawal_limit(who: &T::AccountId) -> Option<WithdrawalLimit<T::BlockNumber>> {
+		if let Some((limit, apply_at)) = Self::pending_withdrawal_limit(who) {
+			if frame_system::Module::<T>::block_number() >= apply_at {
+				PendingWithdrawalLimits::<T>::remove(who);
+				WithdrawalLimits::<T>::set(who, limit.clone());
+				Self::deposit_event(Event::WithdrawalLimitUpdated(who.clone(), limit));
+			}
+		}
+		Self::withdrawal_limit(who)
+	}
+
+	/// Send `amount` of the margin of `who` back to it, the margin left
+	/// covering its IM
+	fn withdraw_margin(who: &T::AccountId, amount: u128) -> DispatchResult {
+		ensure!(!Self::quarantined(who), Error::<T>::AccountIsQuarantined);
+		let new_margin = Self::margin(who).checked_sub(amount).ok_or(Error::<T>::NotEnoughIM)?;
+		ensure!(
+			Self::amount_try_from_balance(new_margin)? >= Self::initial_margin(who, None)?,
+			Error::<T>::NotEnoughIM
+		);
+		T::Currency::transfer(
+			&Self::account_id(),
+			who,
+			Self::balance_to_pallet_balance(amount)?,
+			ExistenceRequirement::KeepAlive,
+		)?;
+		Self::set_margin(who, new_margin)
+	}
+
+	fn remove_pending_withdrawal(who: &T::AccountId, withdrawal_id: WithdrawalId) {
+		PendingWithdrawals::<T>::remove(who, withdrawal_id);
+		PendingWithdrawalCount::<T>::mutate_exists(who, |count| {
+			*count = count.and_then(|count| count.checked_sub(1)).filter(|count| *count > 0);
+		});
+	}
+
+	/// Move `amount` of the position of `who` in `currency_id` to `to`, with
+	/// the share of its margin the position needs. Returns the margin moved.
+	fn do_transfer_position(
+		who: &T::AccountId,
+		currency_id: T::AssetId,
+		to: &T::AccountId,
+		amount: i128,
+	) -> Result<u128, DispatchError> {
+		ensure!(!Self::market_halted(currency_id), Error::<T>::MarketIsHalted);
+		ensure!(!Self::is_expired(currency_id), Error::<T>::MarketExpired);
+		ensure!(
+			!Self::quarantined(who) && !Self::quarantined(to),
+			Error::<T>::AccountIsQuarantined
+		);
+
+		let balance = Self::balances(currency_id, who);
+		ensure!(
+			who != to && amount != 0 && (amount > 0) == (balance > 0),
+			Error::<T>::InvalidTransfer
+		);
+		ensure!(
+			Self::balance_try_from_amount_abs(amount)? <= Self::balance_try_from_amount_abs(balance)?,
+			Error::<T>::InvalidTransfer
+		);
+		let new_balance = balance - amount;
+		let (to_needed_im, to_balance) = Self::get_needed_im(to, &currency_id, amount)?;
+
+		// The margin moved is the share of the sender's IM the position
+		// accounts for, so both keep the same coverage
+		let margin = Self::margin(who);
+		let im_before = Self::balance_try_from_amount_abs(Self::initial_margin(who, None)?)?;
+		let im_after =
+			Self::balance_try_from_amount_abs(Self::initial_margin(who, Some((currency_id, new_balance)))?)?;
+		let moved = if im_before.is_zero() {
+			0
+		} else {
+			multiply_by_rational(margin, im_before.saturating_sub(im_after), im_before)
+				.map_err(|_| Error::<T>::Overflow)?
+		};
+		let new_margin = margin - moved;
+		let to_margin = Self::margin(to).checked_add(moved).ok_or(Error::<T>::Overflow)?;
+		ensure!(new_margin >= im_after, Error::<T>::NotEnoughIM);
+		ensure!(
+			Self::amount_try_from_balance(to_margin)? >= to_needed_im,
+			Error::<T>::NotEnoughIM
+		);
+
+		// Matched inventory moves pro-rata, at the sender's entry price
+		let inventory = Self::inventory(currency_id, who);
+		let moved_inventory = Self::amount_try_from_balance(
+			multiply_by_rational(
+				Self::balance_try_from_amount_abs(inventory)?,
+				Self::balance_try_from_amount_abs(amount)?,
+				Self::balance_try_from_amount_abs(balance)?,
+			)
+			.map_err(|_| Error::<T>::Overflow)?,
+		)?;
+		let moved_inventory = if inventory < 0 { -moved_inventory } else { moved_inventory };
+		let entry_price = Self::position(currency_id, who).entry_price;
+		Self::fill_inventory(currency_id, who, inventory - moved_inventory, entry_price);
+		let to_inventory = Self::inventory(currency_id, to)
+			.checked_add(moved_inventory)
+			.ok_or(Error::<T>::Overflow)?;
+		Self::fill_inventory(currency_id, to, to_inventory, entry_price);
+
+		Self::update_order_block(currency_id, who, balance, new_balance);
+		Self::update_order_block(currency_id, to, to_balance - amount, to_balance);
+		Self::set_balance(currency_id, who, new_balance);
+		Self::set_balance(currency_id, to, to_balance);
+		Self::set_margin(to, to_margin)?;
+		Self::set_margin(who, new_margin)?;
+
+		Self::deposit_event(Event::BalanceUpdated(who.clone(), currency_id, new_balance));
+		Self::deposit_event(Event::BalanceUpdated(to.clone(), currency_id, to_balance));
+
+		Ok(moved)
+	}
+
+	/// Whether `currency_id` is a dated future past its expiry, settled or
+	/// not yet
+	pub fn is_expired(currency_id: T::AssetId) -> bool {
+		Self::settlement_price(currency_id).is_some()
+			|| Self::expiry(currency_id)
+				.map_or(false, |(expiry, _)| frame_system::Module::<T>::block_number() >= expiry)
+	}
+
+	/// How the oversubscribed side of `currency_id` is filled
+	pub fn matching_policy(currency_id: T::AssetId) -> MatchingPolicy {
+		Self::collateral_params(currency_id).matching_policy
+	}
+
+	/// Maximum notional of the vault's position in `currency_id`
+	pub fn vault_risk_limit(currency_id: T::AssetId) -> u128 {
+		Self::collateral_params(currency_id).vault_risk_limit
+	}
+
+	fn get_needed_im(
+		account: &T::AccountId,
+		currency_id: &T::AssetId,
+		amount: i128,
+	) -> result::Result<(i128, i128), Error<T>> {
+		ensure!(Self::listed_assets().contains(currency_id), Error::<T>::BadAssetID);
+		let balance = Self::balances(currency_id, account)
+			.checked_add(amount)
+			.ok_or(Error::<T>::Overflow)?;
+		let res = Self::initial_margin(account, Some((*currency_id, balance)))?;
+		Ok((res, balance))
+	}
+
+	/// IM needed for the positions of `account`, where the balance of
+	/// `overridden.0` is replaced by `overridden.1` if set. Assets the
+	/// account has no position in are skipped, so that one without a price
+	/// does not block every account.
+	fn initial_margin(
+		account: &T::AccountId,
+		overridden: Option<(T::AssetId, i128)>,
+	) -> result::Result<i128, Error<T>> {
+		let mut requirements = Vec::new();
+		for ccy_id in Self::listed_assets() {
+			let balance = match overridden {
+				Some((id, balance)) if id == ccy_id => balance,
+				_ => Self::balances(ccy_id, account),
+			};
+			if balance == 0 {
+				continue;
+			}
+			let price = Self::price0(ccy_id).ok_or(Error::<T>::PriceNotSet)?;
+			let params = Self::collateral_params(ccy_id);
+			ensure!(params.payoff.value(price).is_some(), Error::<T>::InvalidPrice);
+			let size = Self::balance_try_from_amount_abs(balance)?;
+			let value = params.payoff.margin_value(price, size).ok_or(Error::<T>::Overflow)?;
+			let im_floor = Self::leverage(ccy_id, account).map_or_else(Permill::zero, Self::leverage_ratio);
+			let (im, _) = params.requirements(value, im_floor);
+			requirements.push((ccy_id, im, balance < 0));
+		}
+		let total_im_needed = Self::portfolio_requirement(&requirements).ok_or(Error::<T>::Overflow)?;
+		Self::amount_try_from_balance(total_im_needed)
+	}
+
+	/// IM ratio implied by a maximum `leverage`, rounded up
+	fn leverage_ratio(leverage: FixedU128) -> Permill {
+		let inner = leverage.into_inner().max(1);
+		let parts = 1_000_000u128
+			.saturating_mul(FixedU128::accuracy())
+			.saturating_add(inner - 1)
+			/ inner;
+		Permill::from_parts(parts.min(1_000_000) as u32)
+	}
+
+	/// Total of the margin `requirements` of each asset, flagged if short.
+	/// Within an offset group the requirement of the smaller side is reduced
+	/// by the group's offset, other assets add up as they are. `None` on
+	/// overflow.
+	fn portfolio_requirement(requirements: &[(T::AssetId, u128, bool)]) -> Option<u128> {
+		let groups = Self::offset_groups();
+		let mut sides = sp_std::vec![(0u128, 0u128); groups.len()];
+		let mut total = 0u128;
+		for &(currency_id, requirement, short) in requirements {
+			match groups.iter().position(|group| group.assets.contains(&currency_id)) {
+				Some(i) => {
+					let side = if short { &mut sides[i].1 } else { &mut sides[i].0 };
+					*side = side.checked_add(requirement)?;
+				}
+				None => total = total.checked_add(requirement)?,
+			}
+		}
+		for (group, (longs, shorts)) in groups.iter().zip(sides) {
+			let (larger, smaller) = if longs >= shorts { (longs, shorts) } else { (shorts, longs) };
+			// The offset is rounded down so the requirement is rounded up
+			let offset = group.offset.mul_floor(smaller);
+			total = total.checked_add(larger)?.checked_add(smaller - offset)?;
+		}
+		Some(total)
+	}
+
+	/// Call *M* the total margin for a participant *A*,
+	/// Call $T_i$ the total interest in asset *i*, and $B_i$ the inventory
+	/// (open interest is $T_i - B_i$) The needed collateral for maintaining
+	/// the inventory is $\sum_i B_i * P_i * L_i$. If $`sum_i B_i * P_i * L_i >= M$,
+	/// then liquididate the inventory as per below.
+	/// If $\sum_i B_i * P_i * L_i < M$, but $\sum_i T_i * P_i * L_i > M$ then close out
+	/// all open interest, so total interest becomes $\forall i, T_i = B_i$
+	/// and inventory remains at $B_i$
+	/// This is done to make sure that if an opposing open interest comes during
+	/// that block, it does not suffer from immediate liquidation.
+	///
+	/// ### Liquidation of inventory
+	/// If $\sum_i B_i * P_i * L_i >= M$, liquidate all the positions
+	/// so total position and inventory goes to $\forall i, T_i = B_i = 0$
+	///
+	/// Accounts left without positions are closed if their margin is below
+	/// `MinimumMargin`, the dust going to the treasury. Returns the weight of
+	/// the accounts checked.
+	fn liquidate() -> Weight {
+		let assets = Self::listed_assets();
+		let vault = Self::vault_account();
+		let margins: Vec<(T::AccountId, u128)> = Margin::<T>::iter().collect();
+		let weight = T::WeightInfo::liquidate(margins.len().saturating_mul(assets.len()) as u32);
+		for (account, margin) in margins {
+			if Self::quarantined(&account) {
+				continue;
+			}
+			let (liquidation_sum, unwind_sum) = match Self::maintenance_margins(&account, &assets) {
+				Some(sums) => sums,
+				None => {
+					Self::quarantine(&account, None);
+					continue;
+				}
+			};
+
+			// am I in liquidation?
+			if liquidation_sum >= margin {
+				// Yes I am
+				for currency_id in assets.iter().copied() {
+					let balance = Self::balances(currency_id, &account);
+					let inventory = Self::inventory(currency_id, &account);
+					Self::set_balance(currency_id, &account, 0);
+					Self::set_inventory(currency_id, &account, 0);
+					if balance != 0 || inventory != 0 {
+						Self::deposit_event(Event::Liquidated(LiquidationPayload::V1 {
+							account: account.clone(),
+							asset: currency_id,
+							balance,
+							inventory,
+							price: Self::price0(currency_id).unwrap_or_default(),
+							margin,
+						}));
+					}
 				}
 			} else if unwind_sum > margin {
 				// remove open interest
-				for currency_id in T::AssetIds::get() {
+				for currency_id in assets.iter().copied() {
+					let balance = Self::balances(currency_id, &account);
 					let inventory = Self::inventory(currency_id, account.clone());
-					Balances::<T>::insert(currency_id, account.clone(), inventory);
+					Self::set_balance(currency_id, &account, inventory);
+					if balance != inventory {
+						Self::deposit_event(Event::Unwound(LiquidationPayload::V1 {
+							account: account.clone(),
+							asset: currency_id,
+							balance,
+							inventory,
+							price: Self::price0(currency_id).unwrap_or_default(),
+							margin,
+						}));
+					}
+				}
+			}
+
+			if account != vault && !Self::has_positions(&account) {
+				if margin.is_zero() {
+					Self::close_account(&account);
+				} else if margin < T::MinimumMargin::get() {
+					Self::sweep_dust(&account, margin);
 				}
 			}
 		}
+		weight
+	}
+
+	/// Margin needed to maintain the inventory and the balance of `account`,
+	/// or `None` on overflow
+	fn maintenance_margins(account: &T::AccountId, assets: &[T::AssetId]) -> Option<(u128, u128)> {
+		let mut liquidation = Vec::new();
+		let mut unwind = Vec::new();
+		for currency_id in assets.iter().copied() {
+			let inventory = Self::inventory(currency_id, account);
+			let balance = Self::balances(currency_id, account);
+			if inventory == 0 && balance == 0 {
+				continue;
+			}
+			//TODO handle no price better
+			if let Some(price) = Self::price0(currency_id) {
+				let params = Self::collateral_params(currency_id);
+				let inventory_value = params
+					.payoff
+					.margin_value(price, Self::balance_try_from_amount_abs(inventory).ok()?)?;
+				let balance_value = params
+					.payoff
+					.margin_value(price, Self::balance_try_from_amount_abs(balance).ok()?)?;
+				liquidation.push((
+					currency_id,
+					params.requirements(inventory_value, Permill::zero()).1,
+					inventory < 0,
+				));
+				unwind.push((
+					currency_id,
+					params.requirements(balance_value, Permill::zero()).1,
+					balance < 0,
+				));
+			}
+		}
+		Some((
+			Self::portfolio_requirement(&liquidation)?,
+			Self::portfolio_requirement(&unwind)?,
+		))
+	}
+
+	/// Stop marking and liquidating `account` until it is released
+	/// Stop marking and liquidating `account` until it is released,
+	/// recording the price each of its inventories was last marked at:
+	/// `Price0`, or `marked_at` for the asset being marked
+	fn quarantine(account: &T::AccountId, marked_at: Option<(T::AssetId, FixedU128)>) {
+		if Self::quarantined(account) {
+			return;
+		}
+		Quarantined::<T>::insert(account, true);
+		for currency_id in Self::listed_assets() {
+			if Self::inventory(currency_id, account) == 0 {
+				continue;
+			}
+			let price = match marked_at {
+				Some((marked, price)) if marked == currency_id => Some(price),
+				_ => Self::price0(currency_id),
+			};
+			if let Some(price) = price {
+				QuarantinePrices::<T>::insert(account, currency_id, price);
+			}
+		}
+		Self::deposit_event(Event::AccountQuarantined(account.clone()));
+	}
+
+	/// Mark the inventory of the quarantined `account` in `currency_id`
+	/// from the price it was last marked at to `Price0`, so that it funds
+	/// the PnL its counterparties were marked with. `None` on overflow, in
+	/// which case nothing is marked.
+	fn mark_quarantined(account: &T::AccountId, currency_id: T::AssetId) -> Option<()> {
+		let (old_price, new_price) = match (Self::quarantine_price(account, currency_id), Self::price0(currency_id)) {
+			(Some(old_price), Some(new_price)) => (old_price, new_price),
+			_ => return Some(()),
+		};
+		let inventory = Self::inventory(currency_id, account);
+		if inventory == 0 || old_price == new_price {
+			return Some(());
+		}
+		let (up, delta) = Self::collateral_params(currency_id)
+			.payoff
+			.margin_change(old_price, new_price)?;
+		let pnl = Self::mark_pnl(delta, inventory, up)?;
+		let amount = Self::amount_try_from_balance(Self::margin(account))
+			.ok()?
+			.checked_add(pnl)?;
+		let margin = Self::balance_try_from_amount_abs(amount.max(0)).ok()?;
+		Margin::<T>::insert(account, margin);
+		Self::deposit_event(Event::MarginMarked(MarginMarkPayload::V1 {
+			account: account.clone(),
+			asset: currency_id,
+			inventory,
+			old_price,
+			new_price,
+			pnl,
+			margin,
+		}));
+		Some(())
+	}
+
+	/// Stop marking, matching and trading `currency_id` until it is resumed
+	fn halt_market(currency_id: T::AssetId) {
+		if !Self::market_halted(currency_id) {
+			HaltedMarkets::<T>::insert(currency_id, true);
+			Self::deposit_event(Event::MarketHalted(currency_id));
+		}
+	}
+
+	/// Whether `account` has a balance or an inventory in any listed asset
+	fn has_positions(account: &T::AccountId) -> bool {
+		Self::listed_assets()
+			.into_iter()
+			.any(|id| Balances::<T>::contains_key(id, account) || Inventory::<T>::contains_key(id, account))
+	}
+
+	/// Set the margin of `account`. Reserves the storage deposit when the
+	/// entry is created, and closes the account if it is left empty.
+	fn set_margin(account: &T::AccountId, margin: u128) -> DispatchResult {
+		if margin.is_zero() && !Self::has_positions(account) {
+			Self::close_account(account);
+			return Ok(());
+		}
+		if !Margin::<T>::contains_key(account) {
+			let deposit = T::StorageDeposit::get();
+			T::Currency::reserve(account, deposit)?;
+			StorageDeposits::<T>::insert(account, deposit);
+		}
+		Margin::<T>::insert(account, margin);
+		Ok(())
+	}
+
+	/// Remove the margin entry of `account` and release its storage deposit
+	fn close_account(account: &T::AccountId) {
+		Margin::<T>::remove(account);
+		if let Some(deposit) = StorageDeposits::<T>::take(account) {
+			T::Currency::unreserve(account, deposit);
+		}
+	}
+
+	/// Send the margin of an account without positions to the treasury and
+	/// close it
+	fn sweep_dust(account: &T::AccountId, dust: u128) {
+		let amount = match Self::balance_to_pallet_balance(dust) {
+			Ok(amount) => amount,
+			Err(_) => return,
+		};
+		if let Ok(imbalance) = T::Currency::withdraw(
+			&Self::account_id(),
+			amount,
+			WithdrawReasons::TRANSFER,
+			ExistenceRequirement::AllowDeath,
+		) {
+			T::Treasury::on_unbalanced(imbalance);
+			Self::close_account(account);
+			Self::deposit_event(Event::DustSwept(account.clone(), dust));
+		}
 	}
 
 	/// If $\forall i, X_i = 0$ then no interest to match. Otherwise, call $R =
 	/// \frac{\sum_i Y_i}{\sum_i X_i}$ $B_i$ has bought $min(X_i, X_i * R)$
 	/// $S_i$ has sold $min(Y_i, Y_i / R)$
-	fn match_interest(currency_id: T::AssetId) {
-		// TODO: only run if needed
-		// Reset inventory
-		Inventory::<T>::remove_prefix(currency_id);
-		let mut shorts: u128 = 0u128;
-		let mut longs: u128 = 0u128;
-		for balance in Balances::<T>::iter_prefix_values(currency_id) {
-			let b = Self::balance_try_from_amount_abs(balance).unwrap(); // TODO Panics if error
-			if balance < 0 {
-				shorts += b;
-			} else {
-				longs += b;
+	///
+	/// Only runs if a balance changed since the last match. If the fill ratio
+	/// did not move, pro-rata matching only recomputes the inventory of the
+	/// accounts whose balance changed.
+	fn match_interest(currency_id: T::AssetId) -> Weight {
+		Self::rebalance_vault(currency_id);
+		if !Self::needs_matching(currency_id) {
+			return T::WeightInfo::match_interest(0);
+		}
+		NeedsMatching::<T>::remove(currency_id);
+
+		let open_interest = Self::open_interest(currency_id);
+		let last_match = LastMatch::<T>::mutate(currency_id, |last| sp_std::mem::replace(last, open_interest));
+		let changed: Vec<T::AccountId> = ChangedAccounts::<T>::drain_prefix(currency_id)
+			.map(|(account, _)| account)
+			.collect();
+
+		match Self::matched_inventories(currency_id, &changed, &last_match, &open_interest) {
+			Some(inventories) => {
+				Self::deposit_event(Event::Matched(MatchPayload::V1 {
+					asset: currency_id,
+					longs: open_interest.longs,
+					shorts: open_interest.shorts,
+					matched: open_interest.longs.min(open_interest.shorts),
+					price: Self::price0(currency_id).unwrap_or_default(),
+				}));
+				let weight = T::WeightInfo::match_interest(inventories.len() as u32);
+				for (account, inventory) in inventories {
+					Self::set_inventory(currency_id, &account, inventory);
+				}
+				weight
+			}
+			None => {
+				// Leave everything to match again once the market is resumed
+				let weight = T::WeightInfo::match_interest(changed.len() as u32);
+				LastMatch::<T>::insert(currency_id, last_match);
+				for account in changed {
+					ChangedAccounts::<T>::insert(currency_id, account, ());
+				}
+				NeedsMatching::<T>::insert(currency_id, true);
+				Self::halt_market(currency_id);
+				weight
 			}
 		}
+	}
+
+	/// New inventory of the accounts whose inventory may have changed, or
+	/// `None` if it cannot be computed
+	fn matched_inventories(
+		currency_id: T::AssetId,
+		changed: &[T::AccountId],
+		last_match: &OpenInterest,
+		open_interest: &OpenInterest,
+	) -> Option<Vec<(T::AccountId, i128)>> {
+		let fill_ratio = Self::fill_ratio(open_interest);
+		let policy = Self::matching_policy(currency_id);
+
+		// With pro-rata matching, the inventory of an account only depends on
+		// its balance and on the fill ratio
+		if policy == MatchingPolicy::ProRata && Self::fill_ratio(last_match) == fill_ratio {
+			return changed
+				.iter()
+				.map(|account| {
+					let inventory = match fill_ratio {
+						Some((shorts_filled, ratio)) => {
+							Self::pro_rata_inventory(Self::balances(currency_id, account), shorts_filled, ratio).ok()?
+						}
+						None => 0,
+					};
+					Some((account.clone(), inventory))
+				})
+				.collect();
+		}
 
-		// If one of them is 0, nothing to match
-		if shorts != 0 && longs != 0 {
-			let ratio;
-			let shorts_filled;
-			if shorts < longs {
-				ratio = Perquintill::from_rational_approximation(shorts, longs);
-				shorts_filled = true;
+		// Accounts closed since the last match no longer have a balance entry
+		let mut inventories: Vec<(T::AccountId, i128)> = changed
+			.iter()
+			.filter(|account| !Balances::<T>::contains_key(currency_id, *account))
+			.map(|account| (account.clone(), 0))
+			.collect();
+
+		let OpenInterest { longs, shorts } = *open_interest;
+		let (shorts_filled, filled, oversubscribed) = match fill_ratio {
+			Some((true, _)) => (true, shorts, longs),
+			Some((false, _)) => (false, longs, shorts),
+			// If one of them is 0, nothing to match
+			None => {
+				inventories.extend(Balances::<T>::iter_prefix(currency_id).map(|(account, _)| (account, 0)));
+				return Some(inventories);
+			}
+		};
+
+		// The undersubscribed side is fully filled
+		let mut excess: Vec<(T::AccountId, u128)> = Vec::new();
+		for (account, balance) in Balances::<T>::iter_prefix(currency_id) {
+			if (balance < 0) == shorts_filled {
+				inventories.push((account, balance));
 			} else {
-				ratio = Perquintill::from_rational_approximation(longs, shorts);
-				shorts_filled = false;
+				excess.push((account, Self::balance_try_from_amount_abs(balance).ok()?));
+			}
+		}
+
+		let fills = match policy {
+			MatchingPolicy::ProRata => Self::fill_pro_rata(&excess, filled, oversubscribed),
+			MatchingPolicy::LargestRemainder => Self::fill_largest_remainder(&excess, filled, oversubscribed),
+			MatchingPolicy::Fifo => Self::fill_fifo(currency_id, &excess, filled),
+		};
+
+		for ((account, _), fill) in excess.into_iter().zip(fills) {
+			let amount = Self::amount_try_from_balance(fill).ok()?;
+			inventories.push((account, if shorts_filled { amount } else { -amount }));
+		}
+		Some(inventories)
+	}
+
+	/// Set the inventory of `account`, recording the fill in its position at
+	/// the current price
+	fn set_inventory(currency_id: T::AssetId, account: &T::AccountId, inventory: i128) {
+		Self::fill_inventory(
+			currency_id,
+			account,
+			inventory,
+			Self::price0(currency_id).unwrap_or_default(),
+		);
+	}
+
+	/// Set the inventory of `account`, recording the fill in its position at
+	/// `price`
+	fn fill_inventory(currency_id: T::AssetId, account: &T::AccountId, inventory: i128, price: FixedU128) {
+		let old = Self::inventory(currency_id, account);
+		if old == inventory {
+			return;
+		}
+		// Mark the replaced inventory of a quarantined account before
+		// recording the new one against the current price
+		if Self::quarantined(account) && Self::mark_quarantined(account, currency_id).is_some() {
+			match Self::price0(currency_id) {
+				Some(price0) if inventory != 0 => QuarantinePrices::<T>::insert(account, currency_id, price0),
+				_ => QuarantinePrices::<T>::remove(account, currency_id),
 			}
-			for (account, balance) in Balances::<T>::iter_prefix(currency_id) {
-				let mut amount: i128;
-				if (balance < 0 && shorts_filled) || (balance >= 0 && !shorts_filled) {
-					amount = balance;
+		}
+		let payoff = Self::collateral_params(currency_id).payoff;
+		let info = Positions::<T>::mutate(currency_id, account, |info| {
+			Self::record_fill(info, payoff, old, inventory, price);
+			info.clone()
+		});
+		if inventory == 0 {
+			Inventory::<T>::remove(currency_id, account);
+		} else {
+			Inventory::<T>::insert(currency_id, account, inventory);
+		}
+		Self::deposit_event(Event::PositionUpdated(
+			account.clone(),
+			currency_id,
+			inventory,
+			info.entry_price,
+			info.realised_pnl,
+		));
+	}
+
+	/// Update the average entry price of a position going from `old` to `new`
+	/// at `price`, realising the PnL of the part closed if any. The entry of an
+	/// inverse position is the harmonic mean of its fills, so that its PnL in
+	/// the underlying is the sum of the PnL of each fill.
+	fn record_fill(info: &mut PositionInfo, payoff: Payoff, old: i128, new: i128, price: FixedU128) {
+		let same_side = (old > 0 && new > 0) || (old < 0 && new < 0);
+		let old_abs = Self::balance_try_from_amount_abs(old).unwrap_or_default();
+		let new_abs = Self::balance_try_from_amount_abs(new).unwrap_or_default();
+
+		if same_side && new_abs >= old_abs {
+			let old_weight = FixedU128::saturating_from_rational(old_abs, new_abs);
+			let new_weight = FixedU128::saturating_from_rational(new_abs - old_abs, new_abs);
+			let average = |entry: FixedU128, price: FixedU128| {
+				entry
+					.saturating_mul(old_weight)
+					.saturating_add(price.saturating_mul(new_weight))
+			};
+			info.entry_price = match payoff {
+				Payoff::Inverse => payoff
+					.value(info.entry_price)
+					.zip(payoff.value(price))
+					.and_then(|(entry, price)| payoff.value(average(entry, price)))
+					.unwrap_or(price),
+				_ => average(info.entry_price, price),
+			};
+		} else {
+			// Reduced, closed or flipped
+			let closed = if same_side { old_abs - new_abs } else { old_abs };
+			let pnl = Self::pnl(payoff, info.entry_price, price, closed, old < 0);
+			info.realised_pnl = info.realised_pnl.saturating_add(pnl);
+			if new == 0 {
+				info.entry_price = FixedU128::zero();
+			} else if !same_side {
+				info.entry_price = price;
+			}
+		}
+	}
+
+	/// PnL in the margin currency of `size` units bought (or sold if
+	/// `short`) at `entry` and valued at `price`, zero if the payoff is not
+	/// defined at either
+	fn pnl(payoff: Payoff, entry: FixedU128, price: FixedU128, size: u128, short: bool) -> i128 {
+		let gain = match payoff.margin_change(entry, price) {
+			Some((up, delta)) => {
+				let gain = Self::amount_try_from_balance(delta.saturating_mul_int(size)).unwrap_or(i128::MAX);
+				if up {
+					gain
 				} else {
-					let b = Self::balance_try_from_amount_abs(balance).unwrap(); // TODO Panics if error
-					amount = Self::amount_try_from_balance(ratio.mul_floor(b)).unwrap(); // Should never fail given we know no overflow
-					if balance < 0 {
-						amount *= -1;
-					}
+					gain.saturating_neg()
 				}
-				Inventory::<T>::insert(currency_id, account, amount);
 			}
+			None => 0,
+		};
+		if short {
+			gain.saturating_neg()
+		} else {
+			gain
 		}
 	}
 
-	fn update_margin(currency_id: T::AssetId) {
-		// TODO: handle no price better
-		if let Some(new_price) = T::PriceSource::get_price(currency_id) {
-			let p0 = Self::price0(currency_id).unwrap_or(new_price);
-			let multiplier;
-			let delta;
-			if new_price > p0 {
-				multiplier = 1;
-				delta = new_price - p0;
+	/// Positions of `account` in every listed asset it has traded
+	pub fn account_positions(account: &T::AccountId) -> Vec<AccountPosition<T::AssetId>> {
+		Self::listed_assets()
+			.into_iter()
+			.filter_map(|currency_id| {
+				let balance = Self::balances(currency_id, account);
+				let inventory = Self::inventory(currency_id, account);
+				let info = Self::position(currency_id, account);
+				if balance == 0 && inventory == 0 && info.realised_pnl == 0 {
+					return None;
+				}
+				let price = Self::price0(currency_id).unwrap_or_default();
+				let size = Self::balance_try_from_amount_abs(inventory).unwrap_or_default();
+				Some(AccountPosition {
+					asset: currency_id,
+					balance,
+					inventory,
+					entry_price: info.entry_price,
+					realised_pnl: info.realised_pnl,
+					unrealised_pnl: Self::pnl(
+						Self::collateral_params(currency_id).payoff,
+						info.entry_price,
+						price,
+						size,
+						inventory < 0,
+					),
+					leverage: Self::leverage(currency_id, account),
+				})
+			})
+			.collect()
+	}
+
+	/// Whether shorts are the fully filled side, and the ratio at which the
+	/// other side is filled. None if there is nothing to match.
+	fn fill_ratio(open_interest: &OpenInterest) -> Option<(bool, Perquintill)> {
+		let OpenInterest { longs, shorts } = *open_interest;
+		if longs.is_zero() || shorts.is_zero() {
+			None
+		} else if shorts < longs {
+			Some((true, Perquintill::from_rational_approximation(shorts, longs)))
+		} else {
+			Some((false, Perquintill::from_rational_approximation(longs, shorts)))
+		}
+	}
+
+	/// Inventory of `balance` when filled pro-rata
+	fn pro_rata_inventory(balance: i128, shorts_filled: bool, ratio: Perquintill) -> result::Result<i128, Error<T>> {
+		if (balance < 0) == shorts_filled {
+			return Ok(balance);
+		}
+		let amount = Self::amount_try_from_balance(ratio.mul_floor(Self::balance_try_from_amount_abs(balance)?))?;
+		Ok(if balance < 0 { -amount } else { amount })
+	}
+
+	/// Increasing a position from `old` to `new` loses its time priority
+	fn update_order_block(currency_id: T::AssetId, account: &T::AccountId, old: i128, new: i128) {
+		if new == 0 {
+			OrderBlock::<T>::remove(currency_id, account);
+			OrderSequence::<T>::remove(currency_id, account);
+		} else if (new > 0 && new > old) || (new < 0 && new < old) {
+			OrderBlock::<T>::insert(currency_id, account, frame_system::Module::<T>::block_number());
+			let sequence = NextOrderSequence::<T>::mutate(|next| {
+				let sequence = *next;
+				*next = next.saturating_add(1);
+				sequence
+			});
+			OrderSequence::<T>::insert(currency_id, account, sequence);
+		}
+	}
+
+	/// Set the balance of `account`, keeping the open interest of the asset
+	/// up to date and flagging it for matching
+	fn set_balance(currency_id: T::AssetId, account: &T::AccountId, balance: i128) {
+		let old = Self::balances(currency_id, account);
+		if old == balance {
+			return;
+		}
+		let old_abs = Self::balance_try_from_amount_abs(old).unwrap_or_default();
+		let new_abs = Self::balance_try_from_amount_abs(balance).unwrap_or_default();
+		OpenInterests::<T>::mutate(currency_id, |open_interest| {
+			if old < 0 {
+				open_interest.shorts = open_interest.shorts.saturating_sub(old_abs);
 			} else {
-				multiplier = -1;
-				delta = p0 - new_price;
-			}
-			Price0::<T>::insert(currency_id, new_price);
-			if !delta.is_zero() {
-				Margin::<T>::translate(|account, margin: u128| -> Option<u128> {
-					let inventory = Inventory::<T>::get(currency_id, account);
-					let update_inventory = delta.saturating_mul_int(inventory) * multiplier; //TODO is this a problem if it saturates?
-																		 // TODO panic if this fails
-					let mut amount = Self::amount_try_from_balance(margin).unwrap() + update_inventory;
-					if amount < 0 {
-						amount = 0; // No more margin left, account will be liquidated,
-						 // TODO: update margin for everyone
-					}
-					Some(Self::balance_try_from_amount_abs(amount).unwrap()) //TODO
+				open_interest.longs = open_interest.longs.saturating_sub(old_abs);
+			}
+			if balance < 0 {
+				open_interest.shorts = open_interest.shorts.saturating_add(new_abs);
+			} else {
+				open_interest.longs = open_interest.longs.saturating_add(new_abs);
+			}
+		});
+		if balance == 0 {
+			Balances::<T>::remove(currency_id, account);
+		} else {
+			Balances::<T>::insert(currency_id, account, balance);
+		}
+		ChangedAccounts::<T>::insert(currency_id, account, ());
+		NeedsMatching::<T>::insert(currency_id, true);
+	}
+
+	/// Net position of all accounts in the asset, i.e. longs minus shorts
+	fn net_open_interest(currency_id: T::AssetId) -> i128 {
+		let OpenInterest { longs, shorts } = Self::open_interest(currency_id);
+		Self::amount_try_from_balance(longs)
+			.unwrap_or(i128::MAX)
+			.saturating_sub(Self::amount_try_from_balance(shorts).unwrap_or(i128::MAX))
+	}
+
+	/// Fill each balance pro-rata, rounding down
+	fn fill_pro_rata(excess: &[(T::AccountId, u128)], filled: u128, oversubscribed: u128) -> Vec<u128> {
+		let ratio = Perquintill::from_rational_approximation(filled, oversubscribed);
+		excess.iter().map(|(_, b)| ratio.mul_floor(*b)).collect()
+	}
+
+	/// Fill each balance pro-rata, then allocate the rounding dust one unit at
+	/// a time to the balances with the largest remainders, so the total fill
+	/// is exactly `filled`
+	fn fill_largest_remainder(excess: &[(T::AccountId, u128)], filled: u128, oversubscribed: u128) -> Vec<u128> {
+		let mut fills: Vec<u128> = Vec::with_capacity(excess.len());
+		let mut remainders: Vec<(usize, u128)> = Vec::with_capacity(excess.len());
+		for (i, (_, b)) in excess.iter().enumerate() {
+			// Cannot fail as filled < oversubscribed
+			let (fill, remainder) = Self::mul_div_rem(*b, filled, oversubscribed).unwrap_or((0, 0));
+			fills.push(fill);
+			remainders.push((i, remainder));
+		}
+
+		let mut dust = filled.saturating_sub(fills.iter().fold(0u128, |acc, f| acc.saturating_add(*f)));
+		remainders.sort_by(|a, b| b.1.cmp(&a.1));
+		for (i, _) in remainders {
+			if dust.is_zero() {
+				break;
+			}
+			fills[i] += 1;
+			dust -= 1;
+		}
+		fills
+	}
+
+	/// `a * b / c` rounded down and its remainder, without overflowing on
+	/// `a * b`. `None` if `c` is 0 or the quotient does not fit.
+	fn mul_div_rem(a: u128, b: u128, c: u128) -> Option<(u128, u128)> {
+		if c.is_zero() {
+			return None;
+		}
+		if let Some(product) = a.checked_mul(b) {
+			return Some((product / c, product % c));
+		}
+		let mut product = to_big_uint(a).mul(&to_big_uint(b));
+		product.lstrip();
+		let divisor = to_big_uint(c);
+		let quotient: u128 = if divisor.len() == 1 {
+			product.div_unit(c as biguint::Single).try_into().ok()?
+		} else {
+			product.div(&divisor, false)?.0.try_into().ok()?
+		};
+		// The remainder is below `c`, so computing it modulo 2^128 is exact
+		Some((quotient, a.wrapping_mul(b).wrapping_sub(quotient.wrapping_mul(c))))
+	}
+
+	/// Fill balances in order of submission, until `filled` runs out
+	fn fill_fifo(currency_id: T::AssetId, excess: &[(T::AccountId, u128)], filled: u128) -> Vec<u128> {
+		let mut order: Vec<(usize, (T::BlockNumber, u64))> = excess
+			.iter()
+			.enumerate()
+			.map(|(i, (account, _))| {
+				(
+					i,
+					(
+						Self::order_block(currency_id, account),
+						Self::order_sequence(currency_id, account),
+					),
+				)
+			})
+			.collect();
+		order.sort_by(|a, b| a.1.cmp(&b.1));
+
+		let mut fills = sp_std::vec![0u128; excess.len()];
+		let mut remaining = filled;
+		for (i, _) in order {
+			let fill = excess[i].1.min(remaining);
+			fills[i] = fill;
+			remaining -= fill;
+		}
+		fills
+	}
+
+	/// The vault takes the other side of the net position of all other
+	/// accounts, up to its risk limit in notional. It never increases its
+	/// position beyond what its margin covers.
+	fn rebalance_vault(currency_id: T::AssetId) {
+		let vault = Self::vault_account();
+		let limit = Self::vault_risk_limit(currency_id);
+		let current = Self::balances(currency_id, &vault);
+		if limit.is_zero() && current == 0 {
+			return;
+		}
+		let price = match Self::price0(currency_id) {
+			Some(price) if !price.is_zero() => price,
+			_ => return,
+		};
+
+		let net = Self::net_open_interest(currency_id).saturating_sub(current);
+		let max_units = Self::collateral_params(currency_id)
+			.payoff
+			.margin_unit_value(price)
+			.and_then(|value| value.reciprocal())
+			.and_then(|r| r.checked_mul_int(limit))
+			.and_then(|units| TryInto::<i128>::try_into(units).ok())
+			.unwrap_or(i128::MAX);
+		let target = net.saturating_neg().max(-max_units).min(max_units);
+		if target == current {
+			return;
+		}
+
+		let covered = Self::initial_margin(&vault, Some((currency_id, target)))
+			.and_then(Self::balance_try_from_amount_abs)
+			.map(|im| im <= Self::margin(&vault))
+			.unwrap_or(false);
+		if covered {
+			Self::set_balance(currency_id, &vault, target);
+		}
+	}
+
+	/// Move the reward pot to the vault's margin, if it has any liquidity
+	/// provider
+	fn collect_vault_rewards() {
+		if Self::total_vault_shares().is_zero() {
+			return;
+		}
+		let pot = Self::reward_pot_account();
+		let rewards = T::Currency::free_balance(&pot);
+		if rewards.is_zero() {
+			return;
+		}
+		if let Ok(amount) = TryInto::<u128>::try_into(rewards) {
+			if T::Currency::transfer(&pot, &Self::account_id(), rewards, ExistenceRequirement::AllowDeath).is_ok() {
+				Margin::<T>::mutate(Self::vault_account(), |margin| *margin = margin.saturating_add(amount));
+				Self::deposit_event(Event::VaultRewardsCollected(amount));
+			}
+		}
+	}
+
+	/// Mark every account to the current price of `currency_id`, returning
+	/// the weight of the accounts scanned
+	fn update_margin(currency_id: T::AssetId) -> Weight {
+		// TODO: handle no price better
+		let accounts = T::PriceSource::get_price(currency_id)
+			.and_then(|new_price| Self::mark_to(currency_id, new_price))
+			.unwrap_or(0);
+		T::WeightInfo::mark_to(accounts)
+	}
+
+	/// Mark the margin of every account to `new_price` of `currency_id`.
+	/// Returns the number of accounts scanned, or `None` if the market was
+	/// halted instead.
+	fn mark_to(currency_id: T::AssetId, new_price: FixedU128) -> Option<u32> {
+		let old_price = Self::price0(currency_id);
+		let p0 = old_price.unwrap_or(new_price);
+		// The payoff is not defined at the new price, nothing can be
+		// marked until governance fixes the market
+		let (up, delta) = match Self::collateral_params(currency_id).payoff.margin_change(p0, new_price) {
+			Some(change) => change,
+			None => {
+				Self::halt_market(currency_id);
+				return None;
+			}
+		};
+		Price0::<T>::insert(currency_id, new_price);
+		if old_price != Some(new_price) {
+			Self::deposit_event(Event::PriceUpdated(PricePayload::V1 {
+				asset: currency_id,
+				old_price,
+				new_price,
+			}));
+		}
+		let mut accounts = 0u32;
+		if !delta.is_zero() {
+			Margin::<T>::translate(|account, margin: u128| -> Option<u128> {
+				accounts = accounts.saturating_add(1);
+				let inventory = Inventory::<T>::get(currency_id, &account);
+				if inventory == 0 || Self::quarantined(&account) {
+					return Some(margin);
+				}
+				let marked = Self::mark_pnl(delta, inventory, up).and_then(|pnl| {
+					let amount = Self::amount_try_from_balance(margin).ok()?.checked_add(pnl)?;
+					// No more margin left, account will be liquidated
+					// TODO: update margin for everyone
+					let new_margin = Self::balance_try_from_amount_abs(amount.max(0)).ok()?;
+					Some((pnl, new_margin))
 				});
+				match marked {
+					Some((pnl, new_margin)) => {
+						Self::deposit_event(Event::MarginMarked(MarginMarkPayload::V1 {
+							account,
+							asset: currency_id,
+							inventory,
+							old_price: p0,
+							new_price,
+							pnl,
+							margin: new_margin,
+						}));
+						Some(new_margin)
+					}
+					None => {
+						Self::quarantine(&account, Some((currency_id, p0)));
+						Some(margin)
+					}
+				}
+			});
+		}
+		true
+	}
+
+	/// PnL of `inventory` when its unit value moves by `delta`, up if `up`
+	/// is set. Gains are rounded down and losses up, against the account.
+	/// `None` on overflow, in which case the account is quarantined rather
+	/// than marked with a saturated PnL.
+	fn mark_pnl(delta: FixedU128, inventory: i128, up: bool) -> Option<i128> {
+		let size = Self::balance_try_from_amount_abs(inventory).ok()?;
+		if up == (inventory > 0) {
+			Self::amount_try_from_balance(delta.checked_mul_int(size)?).ok()
+		} else {
+			Self::amount_try_from_balance(payoff::mul_int_ceil(delta, size)?)
+				.ok()
+				.map(|loss| -loss)
+		}
+	}
+
+	/// Add the current price of the dated future `currency_id` to its
+	/// settlement average, once per block
+	fn sample_settlement_price(currency_id: T::AssetId, n: T::BlockNumber) {
+		let price = match Self::price0(currency_id) {
+			Some(price) => price,
+			None => return,
+		};
+		SettlementSamples::<T>::mutate(currency_id, |(sum, count, last)| {
+			if *count > 0 && *last == n {
+				return;
+			}
+			*sum = sum.saturating_add(price);
+			*count = count.saturating_add(1);
+			*last = n;
+		});
+	}
+
+	/// Settle every position of the expired dated future `currency_id` at
+	/// the average price over its settlement window, or its last price if
+	/// none was sampled. The margin of each account is marked to that price
+	/// before its position is closed. Returns the weight of the accounts
+	/// marked and closed.
+	fn settle(currency_id: T::AssetId) -> Weight {
+		let (sum, count, _) = SettlementSamples::<T>::get(currency_id);
+		let price = match count {
+			0 => Self::price0(currency_id),
+			_ => Some(FixedU128::from_inner(sum.into_inner() / u128::from(count))),
+		};
+		let price = match price {
+			Some(price) => price,
+			None => {
+				Self::halt_market(currency_id);
+				return T::WeightInfo::settle(0);
+			}
+		};
+		let marked = match Self::mark_to(currency_id, price) {
+			Some(accounts) => accounts,
+			None => return T::WeightInfo::settle(0),
+		};
+
+		let closed = Self::close_positions(currency_id);
+		SettlementSamples::<T>::remove(currency_id);
+		SettlementPrices::<T>::insert(currency_id, price);
+		Self::deposit_event(Event::MarketSettled(currency_id, price));
+		T::WeightInfo::settle(marked.saturating_add(closed))
+	}
+
+	/// Execute the trigger orders of `currency_id` crossed by its price, in
+	/// the order they were placed, through `mint`. Returns the weight of
+	/// scanning the orders and of the trades.
+	fn execute_trigger_orders(currency_id: T::AssetId) -> Weight {
+		let price = match Self::price0(currency_id) {
+			Some(price) => price,
+			None => return 0,
+		};
+		let mut scanned = 0u32;
+		let mut triggered: Vec<(OrderId, TriggerOrder<T::AccountId, PalletBalanceOf<T>>)> =
+			TriggerOrders::<T>::iter_prefix(currency_id)
+				.inspect(|_| scanned = scanned.saturating_add(1))
+				.filter(|(_, order)| order.direction.crossed(order.trigger_price, price))
+				.collect();
+		triggered.sort_by_key(|(order_id, _)| *order_id);
+		let weight = T::WeightInfo::execute_trigger_orders(scanned)
+			.saturating_add(T::WeightInfo::mint_or_burn().saturating_mul(triggered.len() as Weight));
+
+		for (order_id, order) in triggered {
+			Self::remove_trigger_order(currency_id, order_id, &order);
+			Self::deposit_event(Event::TriggerOrderTriggered(
+				order.account.clone(),
+				currency_id,
+				order_id,
+				price,
+			));
+			let result = Self::trigger_amount(currency_id, &order).and_then(|amount| {
+				let origin = frame_system::RawOrigin::Signed(order.account.clone()).into();
+				Self::mint(origin, currency_id, amount, 0, None)
+					.map(|_| amount)
+					.map_err(|e| e.error)
+			});
+			match result {
+				Ok(amount) => Self::deposit_event(Event::TriggerOrderExecuted(
+					order.account,
+					currency_id,
+					order_id,
+					amount,
+				)),
+				Err(error) => {
+					Self::deposit_event(Event::TriggerOrderFailed(order.account, currency_id, order_id, error))
+				}
+			}
+		}
+		weight
+	}
+
+	/// Amount traded by `order`, capped to the position if reduce only
+	fn trigger_amount(
+		currency_id: T::AssetId,
+		order: &TriggerOrder<T::AccountId, PalletBalanceOf<T>>,
+	) -> Result<i128, DispatchError> {
+		if !order.reduce_only {
+			return Ok(order.amount);
+		}
+		let balance = Self::balances(currency_id, &order.account);
+		ensure!(
+			balance != 0 && (balance > 0) != (order.amount > 0),
+			Error::<T>::NotReducing
+		);
+		let closing = balance.saturating_neg();
+		Ok(if order.amount > 0 {
+			order.amount.min(closing)
+		} else {
+			order.amount.max(closing)
+		})
+	}
+
+	/// Remove an order and release its deposit
+	fn remove_trigger_order(
+		currency_id: T::AssetId,
+		order_id: OrderId,
+		order: &TriggerOrder<T::AccountId, PalletBalanceOf<T>>,
+	) {
+		TriggerOrders::<T>::remove(currency_id, order_id);
+		T::Currency::unreserve(&order.account, order.deposit);
+		TriggerOrderCount::<T>::mutate_exists(&order.account, |count| {
+			*count = count.and_then(|count| count.checked_sub(1)).filter(|count| *count > 0);
+		});
+	}
+
+	/// Number of balances, positions, leverages and trigger orders of
+	/// `currency_id`, the entries `delist_asset` removes one by one. Counts
+	/// at most `bound + 1` of each.
+	fn asset_entries(currency_id: T::AssetId, bound: u32) -> u32 {
+		let limit = (bound as usize).saturating_add(1);
+		let count = Balances::<T>::iter_prefix(currency_id)
+			.take(limit)
+			.count()
+			.saturating_add(Positions::<T>::iter_prefix(currency_id).take(limit).count())
+			.saturating_add(Leverages::<T>::iter_prefix(currency_id).take(limit).count())
+			.saturating_add(TriggerOrders::<T>::iter_prefix(currency_id).take(limit).count());
+		count.min(u32::MAX as usize) as u32
+	}
+
+	/// Close every balance and inventory in `currency_id` at its current
+	/// price, the margin being already marked to it, and drop its trigger
+	/// orders. Returns the number of orders and balances closed.
+	fn close_positions(currency_id: T::AssetId) -> u32 {
+		let mut closed = 0u32;
+		for (order_id, order) in TriggerOrders::<T>::iter_prefix(currency_id).collect::<Vec<_>>() {
+			Self::remove_trigger_order(currency_id, order_id, &order);
+			closed = closed.saturating_add(1);
+		}
+		for (account, balance) in Balances::<T>::drain_prefix(currency_id) {
+			closed = closed.saturating_add(1);
+			Self::set_inventory(currency_id, &account, 0);
+			if balance != 0 {
+				Self::deposit_event(Event::PositionSettled(account, currency_id, balance));
 			}
 		}
+		Inventory::<T>::remove_prefix(currency_id);
+		OpenInterests::<T>::remove(currency_id);
+		LastMatch::<T>::remove(currency_id);
+		ChangedAccounts::<T>::remove_prefix(currency_id);
+		NeedsMatching::<T>::remove(currency_id);
+		OrderBlock::<T>::remove_prefix(currency_id);
+		OrderSequence::<T>::remove_prefix(currency_id);
+		closed
+	}
+
+	/// A trade is a maker if it brings the net position of the asset, i.e.
+	/// longs minus shorts, closer to 0
+	fn liquidity_of(currency_id: T::AssetId, amount: i128) -> Liquidity {
+		let net = Self::net_open_interest(currency_id);
+		if net.saturating_add(amount).saturating_abs() < net.saturating_abs() {
+			Liquidity::Maker
+		} else {
+			Liquidity::Taker
+		}
+	}
+
+	/// Fee rate applying to `account`, given its rolling traded volume
+	fn fee_rate(account: &T::AccountId, currency_id: T::AssetId, liquidity: Liquidity) -> Permill {
+		let volume = Self::rolling_volume(account);
+		Self::fee_tiers()
+			.iter()
+			.rev()
+			.find(|tier| volume >= tier.min_volume)
+			.map(|tier| match liquidity {
+				Liquidity::Maker => tier.maker_fee,
+				Liquidity::Taker => tier.taker_fee,
+			})
+			.unwrap_or_else(|| Self::collateral_params(currency_id).transaction_fee)
+	}
+
+	fn current_volume_bucket() -> T::BlockNumber {
+		let now = frame_system::Module::<T>::block_number();
+		now.checked_div(&T::VolumeBucketLength::get()).unwrap_or(now)
+	}
+
+	/// Traded notional of `account` over the last `VolumeBuckets` buckets
+	pub fn rolling_volume(account: &T::AccountId) -> u128 {
+		let bucket = Self::current_volume_bucket();
+		let window: T::BlockNumber = T::VolumeBuckets::get().into();
+		Self::traded_volume(account)
+			.iter()
+			.filter(|(b, _)| *b + window > bucket)
+			.fold(0u128, |acc, (_, volume)| acc.saturating_add(*volume))
+	}
+
+	fn record_volume(account: &T::AccountId, notional: u128) {
+		let bucket = Self::current_volume_bucket();
+		let window: T::BlockNumber = T::VolumeBuckets::get().into();
+		TradedVolume::<T>::mutate(account, |buckets| {
+			buckets.retain(|(b, _)| *b + window > bucket);
+			match buckets.last_mut() {
+				Some((b, volume)) if *b == bucket => *volume = volume.saturating_add(notional),
+				_ => buckets.push((bucket, notional)),
+			}
+		});
+	}
+
+	/// Pay the referral reward and rebate of `fee` paid by `account`, then
+	/// split the remainder between the treasury, the insurance fund and the
+	/// reward pot as per `FeeSplitParams`
+	fn distribute_fee(account: &T::AccountId, imbalance: NegativeImbalanceOf<T>, fee: u128) -> DispatchResult {
+		let referrals = match Self::referrer_of(account) {
+			Some(referrer) => {
+				let params = Self::referral_params();
+				let reward = params.referrer_share.mul_floor(fee);
+				let rebate = params.trader_rebate.mul_floor(fee);
+				if !reward.is_zero() {
+					PendingRewards::<T>::mutate(&referrer, |r| *r = r.saturating_add(reward));
+					Self::deposit_event(Event::ReferralRewardAccrued(referrer, account.clone(), reward));
+				}
+				if !rebate.is_zero() {
+					PendingRewards::<T>::mutate(account, |r| *r = r.saturating_add(rebate));
+					Self::deposit_event(Event::RebateAccrued(account.clone(), rebate));
+				}
+				reward.saturating_add(rebate)
+			}
+			None => 0,
+		};
+		let (referral_imbalance, imbalance) = imbalance.split(Self::balance_to_pallet_balance(referrals)?);
+		T::Currency::resolve_creating(&Self::referral_pot_account(), referral_imbalance);
+
+		let fee = fee.saturating_sub(referrals);
+		let split = Self::fee_split();
+		let insurance = split.insurance.mul_floor(fee);
+		let rewards = split.rewards.mul_floor(fee);
+		let treasury = fee.saturating_sub(insurance).saturating_sub(rewards);
+
+		let (insurance_imbalance, rest) = imbalance.split(Self::balance_to_pallet_balance(insurance)?);
+		let (rewards_imbalance, treasury_imbalance) = rest.split(Self::balance_to_pallet_balance(rewards)?);
+
+		T::Currency::resolve_creating(&Self::insurance_account(), insurance_imbalance);
+		T::Currency::resolve_creating(&Self::reward_pot_account(), rewards_imbalance);
+		T::Treasury::on_unbalanced(treasury_imbalance);
+
+		BlockFees::<T>::mutate(|fees| {
+			fees.treasury = fees.treasury.saturating_add(treasury);
+			fees.insurance = fees.insurance.saturating_add(insurance);
+			fees.rewards = fees.rewards.saturating_add(rewards);
+			fees.referrals = fees.referrals.saturating_add(referrals);
+		});
+
+		Ok(())
 	}
 
 	fn account_id() -> T::AccountId {
 		T::ModuleId::get().into_account()
 	}
 
+	/// Account holding the insurance fund
+	pub fn insurance_account() -> T::AccountId {
+		T::ModuleId::get().into_sub_account(b"insurance")
+	}
+
+	/// Account holding the liquidity providers' reward pot
+	pub fn reward_pot_account() -> T::AccountId {
+		T::ModuleId::get().into_sub_account(b"rewards")
+	}
+
+	/// Account of the liquidity providers' vault, its collateral is held in
+	/// the module's account like any other margin
+	pub fn vault_account() -> T::AccountId {
+		T::ModuleId::get().into_sub_account(b"vault")
+	}
+
+	/// Whether `account` is the pallet's account or one of its
+	/// sub-accounts, such as the vault, the pots and the accounts of
+	/// position tokens
+	fn is_pallet_account(account: &T::AccountId) -> bool {
+		let prefix = (ModuleId::TYPE_ID, T::ModuleId::get()).encode();
+		account.using_encoded(|encoded| {
+			let len = prefix.len().min(encoded.len());
+			encoded[..len] == prefix[..len]
+		})
+	}
+
+	/// Give the position token `token_id` owned by `from` to `to`
+	#[transactional]
+	fn do_transfer_position_token(token_id: PositionTokenId, from: &T::AccountId, to: &T::AccountId) -> DispatchResult {
+		ensure!(!Self::is_pallet_account(to), Error::<T>::InvalidTransfer);
+		PositionTokens::<T>::try_mutate(token_id, |token| -> DispatchResult {
+			let token = token.as_mut().ok_or(Error::<T>::PositionTokenNotFound)?;
+			ensure!(token.owner == *from, Error::<T>::NotTokenOwner);
+			token.owner = to.clone();
+			Ok(())
+		})?;
+		Self::limit_margin_transfer(from, Self::margin(&Self::position_token_account(token_id)))?;
+		Self::deposit_event(Event::PositionTokenTransferred(token_id, from.clone(), to.clone()));
+		Ok(())
+	}
+
+	/// Account holding the position and margin of a position token
+	pub fn position_token_account(token_id: PositionTokenId) -> T::AccountId {
+		T::ModuleId::get().into_sub_account(("position", token_id))
+	}
+
+	/// Owner, position and margin of a position token
+	pub fn wrapped_position(
+		token_id: PositionTokenId,
+	) -> Option<(T::AccountId, Option<AccountPosition<T::AssetId>>, u128)> {
+		let token = Self::position_token(token_id)?;
+		let account = Self::position_token_account(token_id);
+		let position = Self::account_positions(&account)
+			.into_iter()
+			.find(|position| position.asset == token.asset);
+		Some((token.owner, position, Self::margin(&account)))
+	}
+
+	/// Account holding the unclaimed referral rewards and rebates
+	pub fn referral_pot_account() -> T::AccountId {
+		T::ModuleId::get().into_sub_account(b"referral")
+	}
+
 	/// Gets the total balance of collateral in NativeCurrency
 	pub fn total_collateral_balance() -> PalletBalanceOf<T> {
 		T::Currency::total_balance(&Self::account_id())
@@ -545,6 +3755,24 @@ impl<T: Config> Pallet<T> {
 	}
 }
 
+impl<T: Config> PositionTokenRegistry<T::AccountId> for Pallet<T> {
+	type AssetId = T::AssetId;
+
+	fn token(token_id: PositionTokenId) -> Option<(T::AccountId, T::AssetId)> {
+		Self::position_token(token_id).map(|token| (token.owner, token.asset))
+	}
+
+	fn holdings(token_id: PositionTokenId) -> Option<(i128, u128)> {
+		let token = Self::position_token(token_id)?;
+		let account = Self::position_token_account(token_id);
+		Some((Self::balances(token.asset, &account), Self::margin(&account)))
+	}
+
+	fn transfer(token_id: PositionTokenId, from: &T::AccountId, to: &T::AccountId) -> DispatchResult {
+		Self::do_transfer_position_token(token_id, from, to)
+	}
+}
+
 #[cfg(feature = "std")]
 impl<T: Config> GenesisConfig<T> {
 	/// Direct implementation of `GenesisBuild::build_storage`.
@@ -0,0 +1,339 @@
+// Copyright (C) 2021 Georges Dib.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Storage migrations for the perpetuals module.
+//!
+//! Each layout change bumps `Releases` and adds a module below with its
+//! `migrate` function. `migrate` runs every migration newer than the
+//! stored version, in order, so a chain can skip several upgrades.
+
+use super::*;
+use frame_support::traits::PalletInfo;
+
+/// Run all the migrations the stored version is missing
+pub fn migrate<T: Config>() -> Weight {
+	let version = StorageVersion::<T>::get();
+	let mut weight = T::DbWeight::get().reads(1);
+
+	if version < Releases::V2_0_0 {
+		weight = weight.saturating_add(v2::migrate::<T>());
+	}
+	if version < Releases::V3_0_0 {
+		weight = weight.saturating_add(v3::migrate::<T>());
+	}
+	if version < Releases::V4_0_0 {
+		weight = weight.saturating_add(v4::migrate::<T>());
+		StorageVersion::<T>::put(Releases::V4_0_0);
+		weight = weight.saturating_add(T::DbWeight::get().writes(1));
+	}
+
+	weight
+}
+
+/// Checks to run before `migrate`
+#[cfg(any(feature = "try-runtime", test))]
+pub fn pre_upgrade<T: Config>() -> Result<(), &'static str> {
+	match StorageVersion::<T>::get() {
+		Releases::V1_0_0 => v2::pre_upgrade::<T>(),
+		Releases::V2_0_0 => v3::pre_upgrade::<T>(),
+		Releases::V3_0_0 => v4::pre_upgrade::<T>(),
+		Releases::V4_0_0 => Ok(()),
+	}
+}
+
+/// Checks to run after `migrate`
+#[cfg(any(feature = "try-runtime", test))]
+pub fn post_upgrade<T: Config>() -> Result<(), &'static str> {
+	ensure!(
+		StorageVersion::<T>::get() == Releases::V4_0_0,
+		"storage version not updated"
+	);
+	v2::post_upgrade::<T>()?;
+	v3::post_upgrade::<T>()?;
+	v4::post_upgrade::<T>()
+}
+
+/// Migrate the layout of the first release. Its assets were given by the
+/// `AssetIds` constant, which `ListedAssets` replaced, their `AssetParams`
+/// had no matching policy nor vault risk limit, and balances were kept
+/// without the aggregates later releases update incrementally.
+pub mod v2 {
+	use super::*;
+	use frame_support::storage::{migration::StorageIterator, unhashed};
+
+	/// `AssetParams` as stored in `V1_0_0`
+	#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq, Default)]
+	pub struct OldAssetParams {
+		pub initial_im_ratio: Permill,
+		pub liquidation_ratio: Permill,
+		pub transaction_fee: Permill,
+	}
+
+	/// Prefix of the module's storage
+	pub fn module_prefix<T: Config>() -> &'static [u8] {
+		<T as frame_system::Config>::PalletInfo::name::<Pallet<T>>()
+			.unwrap_or("PerpetualsExchange")
+			.as_bytes()
+	}
+
+	/// Assets of the `V1_0_0` layout and their params. `AssetIds` is gone,
+	/// but the assets it listed have their params in `CollateralParams`, or
+	/// a price recorded by `on_initialize` if they traded with the default,
+	/// zero, params.
+	pub fn old_assets<T: Config>() -> Vec<(T::AssetId, OldAssetParams)> {
+		let mut assets: Vec<(T::AssetId, OldAssetParams)> =
+			StorageIterator::<OldAssetParams>::new(module_prefix::<T>(), b"CollateralParams")
+				.filter_map(|(key, params)| {
+					// Keys are hashed with `Twox64Concat`
+					let currency_id = T::AssetId::decode(&mut key.get(8..)?).ok()?;
+					Some((currency_id, params))
+				})
+				.collect();
+		for (currency_id, _) in Price0::<T>::iter() {
+			if !assets.iter().any(|(id, _)| *id == currency_id) {
+				assets.push((currency_id, OldAssetParams::default()));
+			}
+		}
+		assets
+	}
+
+	/// Writes the `V2_0_0` layout, as later migrations expect it
+	pub fn migrate<T: Config>() -> Weight {
+		let assets = old_assets::<T>();
+		let mut reads = (assets.len() as u64).saturating_mul(2).saturating_add(1);
+		let mut writes = (assets.len() as u64).saturating_add(1);
+
+		for (currency_id, old) in assets.iter() {
+			unhashed::put(
+				&CollateralParams::<T>::hashed_key_for(currency_id),
+				&v3::OldAssetParams {
+					initial_im_ratio: old.initial_im_ratio,
+					liquidation_ratio: old.liquidation_ratio,
+					transaction_fee: old.transaction_fee,
+					matching_policy: MatchingPolicy::default(),
+					vault_risk_limit: 0,
+				},
+			);
+			let (r, w) = set_up_bookkeeping::<T>(*currency_id);
+			reads = reads.saturating_add(r);
+			writes = writes.saturating_add(w);
+		}
+		ListedAssets::<T>::mutate(|listed| {
+			for (currency_id, _) in assets {
+				if !listed.contains(&currency_id) {
+					listed.push(currency_id);
+				}
+			}
+		});
+
+		T::DbWeight::get().reads_writes(reads, writes)
+	}
+
+	/// Remove the zero balances and inventories `V1_0_0` kept, and record
+	/// the open interest and entry prices it did not track. `V1_0_0` matched
+	/// every asset each block, so it is left as just matched, positions
+	/// being entered at the price their margin was last marked to. Returns
+	/// the reads and writes done.
+	fn set_up_bookkeeping<T: Config>(currency_id: T::AssetId) -> (u64, u64) {
+		let price = Pallet::<T>::price0(currency_id).unwrap_or_default();
+		let mut open_interest = OpenInterest::default();
+		let (mut reads, mut writes) = (1u64, 2u64);
+
+		for (account, balance) in Balances::<T>::iter_prefix(currency_id).collect::<Vec<_>>() {
+			reads += 1;
+			let size = balance.saturating_abs() as u128;
+			if balance == 0 {
+				writes += 1;
+				Balances::<T>::remove(currency_id, &account);
+			} else if balance < 0 {
+				open_interest.shorts = open_interest.shorts.saturating_add(size);
+			} else {
+				open_interest.longs = open_interest.longs.saturating_add(size);
+			}
+		}
+		for (account, inventory) in Inventory::<T>::iter_prefix(currency_id).collect::<Vec<_>>() {
+			reads += 1;
+			writes += 1;
+			if inventory == 0 {
+				Inventory::<T>::remove(currency_id, &account);
+			} else {
+				Positions::<T>::mutate(currency_id, &account, |position| position.entry_price = price);
+			}
+		}
+		OpenInterests::<T>::insert(currency_id, open_interest);
+		LastMatch::<T>::insert(currency_id, open_interest);
+
+		(reads, writes)
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn pre_upgrade<T: Config>() -> Result<(), &'static str> {
+		ensure!(
+			Margin::<T>::iter().next().is_none() || !old_assets::<T>().is_empty(),
+			"accounts with margin but no V1 assets"
+		);
+		Ok(())
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn post_upgrade<T: Config>() -> Result<(), &'static str> {
+		for currency_id in Pallet::<T>::listed_assets() {
+			ensure!(
+				CollateralParams::<T>::try_get(currency_id).is_ok(),
+				"listed asset without V2 params"
+			);
+			let mut open_interest = OpenInterest::default();
+			for (_, balance) in Balances::<T>::iter_prefix(currency_id) {
+				ensure!(balance != 0, "zero balance left");
+				let size = balance.saturating_abs() as u128;
+				if balance < 0 {
+					open_interest.shorts = open_interest.shorts.saturating_add(size);
+				} else {
+					open_interest.longs = open_interest.longs.saturating_add(size);
+				}
+			}
+			ensure!(
+				Pallet::<T>::open_interest(currency_id) == open_interest,
+				"open interest does not match the balances"
+			);
+		}
+		Ok(())
+	}
+}
+
+/// Add the payoff of each asset to its `AssetParams`, existing markets being
+/// linear
+pub mod v3 {
+	use super::*;
+	use frame_support::storage::unhashed;
+
+	/// `AssetParams` as stored in `V2_0_0`
+	#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+	pub struct OldAssetParams {
+		pub initial_im_ratio: Permill,
+		pub liquidation_ratio: Permill,
+		pub transaction_fee: Permill,
+		pub matching_policy: MatchingPolicy,
+		pub vault_risk_limit: u128,
+	}
+
+	/// Writes the `V3_0_0` layout, as later migrations expect it
+	pub fn migrate<T: Config>() -> Weight {
+		let mut translated = 0u64;
+
+		for currency_id in Pallet::<T>::listed_assets() {
+			let storage_key = CollateralParams::<T>::hashed_key_for(currency_id);
+			if let Some(old) = unhashed::get::<OldAssetParams>(&storage_key) {
+				translated += 1;
+				unhashed::put(
+					&storage_key,
+					&v4::OldAssetParams {
+						initial_im_ratio: old.initial_im_ratio,
+						liquidation_ratio: old.liquidation_ratio,
+						transaction_fee: old.transaction_fee,
+						matching_policy: old.matching_policy,
+						vault_risk_limit: old.vault_risk_limit,
+						payoff: Payoff::Linear,
+					},
+				);
+			}
+		}
+
+		T::DbWeight::get().reads_writes(translated + 1, translated)
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn pre_upgrade<T: Config>() -> Result<(), &'static str> {
+		for currency_id in Pallet::<T>::listed_assets() {
+			let key = CollateralParams::<T>::hashed_key_for(currency_id);
+			ensure!(
+				unhashed::get::<OldAssetParams>(&key).is_some(),
+				"listed asset without V2 params"
+			);
+		}
+		Ok(())
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn post_upgrade<T: Config>() -> Result<(), &'static str> {
+		for currency_id in Pallet::<T>::listed_assets() {
+			ensure!(
+				CollateralParams::<T>::try_get(currency_id).is_ok(),
+				"listed asset without V3 params"
+			);
+		}
+		Ok(())
+	}
+}
+
+/// Add risk brackets to each asset's `AssetParams`, existing markets having
+/// flat ratios
+pub mod v4 {
+	use super::*;
+
+	/// `AssetParams` as stored in `V3_0_0`
+	#[derive(Encode, Decode, Clone, RuntimeDebug, PartialEq, Eq)]
+	pub struct OldAssetParams {
+		pub initial_im_ratio: Permill,
+		pub liquidation_ratio: Permill,
+		pub transaction_fee: Permill,
+		pub matching_policy: MatchingPolicy,
+		pub vault_risk_limit: u128,
+		pub payoff: Payoff,
+	}
+
+	pub fn migrate<T: Config>() -> Weight {
+		let mut translated = 0u64;
+
+		CollateralParams::<T>::translate::<OldAssetParams, _>(|_, old| {
+			translated += 1;
+			Some(AssetParams {
+				initial_im_ratio: old.initial_im_ratio,
+				liquidation_ratio: old.liquidation_ratio,
+				transaction_fee: old.transaction_fee,
+				matching_policy: old.matching_policy,
+				vault_risk_limit: old.vault_risk_limit,
+				payoff: old.payoff,
+				brackets: Vec::new(),
+			})
+		});
+
+		T::DbWeight::get().reads_writes(translated, translated)
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn pre_upgrade<T: Config>() -> Result<(), &'static str> {
+		for currency_id in Pallet::<T>::listed_assets() {
+			let key = CollateralParams::<T>::hashed_key_for(currency_id);
+			ensure!(
+				frame_support::storage::unhashed::get::<OldAssetParams>(&key).is_some(),
+				"listed asset without V3 params"
+			);
+		}
+		Ok(())
+	}
+
+	#[cfg(any(feature = "try-runtime", test))]
+	pub fn post_upgrade<T: Config>() -> Result<(), &'static str> {
+		for currency_id in Pallet::<T>::listed_assets() {
+			ensure!(
+				CollateralParams::<T>::try_get(currency_id).is_ok(),
+				"listed asset without V4 params"
+			);
+		}
+		Ok(())
+	}
+}
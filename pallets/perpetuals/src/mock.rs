@@ -60,7 +60,12 @@ parameter_types!(
 	pub const PerpetualAssetModuleId: ModuleId = ModuleId(*b"aca/pasm");
 	pub const PerpetualsTreasuryPalletId: ModuleId = ModuleId(*b"aca/ptsy");
 	pub const NativeCurrencyId: CurrencyId = CurrencyId::KUSD;
-	pub AssetIds: Vec<CurrencyId> = vec![CurrencyId::DOT, CurrencyId::LDOT];
+	pub const VolumeBuckets: u32 = 3;
+	pub const VaultCooldown: BlockNumber = 5;
+	pub const SettlementWindow: BlockNumber = 3;
+	pub const MaxTriggerOrders: u32 = 2;
+	pub const TriggerOrderDeposit: Balance = 5;
+	pub const MaxPendingWithdrawals: u32 = 2;
 );
 
 impl frame_system::Config for Runtime {
@@ -119,6 +124,66 @@ impl pallet_balances::Config for Runtime {
 thread_local! {
 	static PRICE_DOT: RefCell<Option<FixedU128>> = RefCell::new(Some(FixedU128::one()));
 	static PRICE_LDOT: RefCell<Option<FixedU128>> = RefCell::new(Some(FixedU128::one()));
+	static MINIMUM_MARGIN: RefCell<u128> = RefCell::new(0);
+	static STORAGE_DEPOSIT: RefCell<Balance> = RefCell::new(0);
+	static WITHDRAWAL_PERIOD: RefCell<BlockNumber> = RefCell::new(10);
+	static VOLUME_BUCKET_LENGTH: RefCell<BlockNumber> = RefCell::new(10);
+}
+
+pub struct MinimumMargin;
+
+impl MinimumMargin {
+	pub fn set(margin: u128) {
+		MINIMUM_MARGIN.with(|v| *v.borrow_mut() = margin);
+	}
+}
+
+impl Get<u128> for MinimumMargin {
+	fn get() -> u128 {
+		MINIMUM_MARGIN.with(|v| *v.borrow())
+	}
+}
+
+pub struct StorageDeposit;
+
+impl StorageDeposit {
+	pub fn set(deposit: Balance) {
+		STORAGE_DEPOSIT.with(|v| *v.borrow_mut() = deposit);
+	}
+}
+
+impl Get<Balance> for StorageDeposit {
+	fn get() -> Balance {
+		STORAGE_DEPOSIT.with(|v| *v.borrow())
+	}
+}
+
+pub struct VolumeBucketLength;
+
+impl VolumeBucketLength {
+	pub fn set(length: BlockNumber) {
+		VOLUME_BUCKET_LENGTH.with(|v| *v.borrow_mut() = length);
+	}
+}
+
+impl Get<BlockNumber> for VolumeBucketLength {
+	fn get() -> BlockNumber {
+		VOLUME_BUCKET_LENGTH.with(|v| *v.borrow())
+	}
+}
+
+pub struct WithdrawalPeriod;
+
+impl WithdrawalPeriod {
+	pub fn set(period: BlockNumber) {
+		WITHDRAWAL_PERIOD.with(|v| *v.borrow_mut() = period);
+	}
+}
+
+impl Get<BlockNumber> for WithdrawalPeriod {
+	fn get() -> BlockNumber {
+		WITHDRAWAL_PERIOD.with(|v| *v.borrow())
+	}
 }
 
 pub struct MockPriceSource;
@@ -143,14 +208,47 @@ impl PriceProvider<CurrencyId> for MockPriceSource {
 	}
 }
 
+/// Stands in for another pallet taking position tokens as collateral,
+/// which only sees them through `PositionTokenRegistry`
+pub struct MockLender;
+
+impl MockLender {
+	pub fn account() -> AccountId {
+		ModuleId(*b"aca/lend").into_account()
+	}
+
+	/// Take `token_id` from `owner` if it holds a long position, returning
+	/// the margin lent against it
+	pub fn borrow<R: PositionTokenRegistry<AccountId>>(owner: &AccountId, token_id: PositionTokenId) -> Result<u128, DispatchError> {
+		let (balance, margin) = R::holdings(token_id).ok_or(DispatchError::Other("no token"))?;
+		ensure!(balance > 0, DispatchError::Other("not long"));
+		R::transfer(token_id, owner, &Self::account())?;
+		Ok(margin / 2)
+	}
+
+	/// Give `token_id` back to `owner`
+	pub fn repay<R: PositionTokenRegistry<AccountId>>(owner: &AccountId, token_id: PositionTokenId) -> DispatchResult {
+		R::transfer(token_id, &Self::account(), owner)
+	}
+}
+
 impl ecosystem_perpetuals_exchange::Config for Runtime {
 	type Event = Event;
 	type UpdateOrigin = EnsureSignedBy<Alice, AccountId>;
 	type AssetId = CurrencyId;
 	type ModuleId = PerpetualAssetModuleId;
 	type Currency = Balances;
-	type AssetIds = AssetIds;
 	type PriceSource = MockPriceSource;
+	type VolumeBucketLength = VolumeBucketLength;
+	type VolumeBuckets = VolumeBuckets;
+	type VaultCooldown = VaultCooldown;
+	type MinimumMargin = MinimumMargin;
+	type StorageDeposit = StorageDeposit;
+	type SettlementWindow = SettlementWindow;
+	type MaxTriggerOrders = MaxTriggerOrders;
+	type TriggerOrderDeposit = TriggerOrderDeposit;
+	type WithdrawalPeriod = WithdrawalPeriod;
+	type MaxPendingWithdrawals = MaxPendingWithdrawals;
 	type Treasury = Treasury;
 	type WeightInfo = ();
 }
@@ -0,0 +1,127 @@
+// Copyright (C) 2021 Georges Dib.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Payoffs of perpetual markets.
+//!
+//! A payoff maps the oracle price to the value of one unit of the market.
+//! Margin requirements are a ratio of that value, and margin is marked by
+//! its change between two prices.
+
+use codec::{Decode, Encode};
+use sp_runtime::{FixedPointNumber, FixedU128, RuntimeDebug};
+
+/// Value of one unit of a market as a function of the price $P$
+#[derive(Encode, Decode, Clone, Copy, RuntimeDebug, PartialEq, Eq)]
+pub enum Payoff {
+	/// $P$
+	Linear,
+	/// $-1 / P$, so a long gains $1 / P_0 - 1 / P_1$ per unit. Used for
+	/// contracts quoted in the quote currency and paying in the asset.
+	Inverse,
+	/// $P^2$, a power perpetual
+	Quadratic,
+	/// $min(P, cap)$
+	Capped { cap: FixedU128 },
+}
+
+impl Default for Payoff {
+	fn default() -> Self {
+		Payoff::Linear
+	}
+}
+
+impl Payoff {
+	/// Absolute value of one unit at `price`, rounded up. `None` on overflow
+	/// or if the payoff is not defined at `price`.
+	pub fn value(&self, price: FixedU128) -> Option<FixedU128> {
+		match self {
+			Payoff::Linear => Some(price),
+			Payoff::Inverse => {
+				let p = price.into_inner();
+				if p == 0 {
+					return None;
+				}
+				let one = FixedU128::accuracy().checked_mul(FixedU128::accuracy())?;
+				let rounding = if one % p == 0 { 0 } else { 1 };
+				Some(FixedU128::from_inner(one / p + rounding))
+			}
+			Payoff::Quadratic => mul_int_ceil(price, price.into_inner()).map(FixedU128::from_inner),
+			Payoff::Capped { cap } => Some(price.min(*cap)),
+		}
+	}
+
+	/// Value of one unit at `price` in the margin currency. An inverse unit
+	/// pays $1 / P$ of the asset, worth exactly one unit of currency at $P$.
+	/// `None` on overflow or if the payoff is not defined at `price`.
+	pub fn margin_unit_value(&self, price: FixedU128) -> Option<FixedU128> {
+		let value = self.value(price)?;
+		match self {
+			Payoff::Inverse => Some(FixedU128::one()),
+			_ => Some(value),
+		}
+	}
+
+	/// Absolute value of `size` units at `price` in the margin currency,
+	/// rounded up. `None` on overflow or if the payoff is not defined at
+	/// `price`.
+	pub fn margin_value(&self, price: FixedU128, size: u128) -> Option<u128> {
+		mul_int_ceil(self.margin_unit_value(price)?, size)
+	}
+
+	/// Change in the margin currency of one unit when the price moves from
+	/// `from` to `to`, as whether it went up and by how much. The change of
+	/// an inverse unit is in the asset and converted at `to`, which gives
+	/// $|1 / P_0 - 1 / P_1| P_1 = |P_1 - P_0| / P_0$.
+	pub fn margin_change(&self, from: FixedU128, to: FixedU128) -> Option<(bool, FixedU128)> {
+		let (up, delta) = self.change(from, to)?;
+		match self {
+			Payoff::Inverse => {
+				let difference = if to > from { to - from } else { from - to };
+				Some((up, difference.checked_div(&from)?))
+			}
+			_ => Some((up, delta)),
+		}
+	}
+
+	/// Change in the value of one unit when the price moves from `from` to
+	/// `to`, as whether it went up and by how much. `None` if the payoff is
+	/// not defined at either price.
+	pub fn change(&self, from: FixedU128, to: FixedU128) -> Option<(bool, FixedU128)> {
+		let (a, b) = (self.value(from)?, self.value(to)?);
+		// The value of an inverse unit is negative, so it goes up when its
+		// absolute value goes down
+		let up = match self {
+			Payoff::Inverse => b < a,
+			_ => b > a,
+		};
+		let delta = if a > b { a - b } else { b - a };
+		Some((up, delta))
+	}
+}
+
+/// `price * amount` rounded up, or `None` on overflow. Used wherever
+/// rounding down would favour the account, i.e. margin requirements and
+/// losses.
+pub fn mul_int_ceil(price: FixedU128, amount: u128) -> Option<u128> {
+	let floor = price.checked_mul_int(amount)?;
+	// (a * b) mod d, computed on the residues so it cannot overflow
+	let accuracy = FixedU128::accuracy();
+	if (price.into_inner() % accuracy) * (amount % accuracy) % accuracy == 0 {
+		Some(floor)
+	} else {
+		floor.checked_add(1)
+	}
+}
@@ -21,8 +21,8 @@
 use super::*;
 use frame_support::{assert_noop, assert_ok, error::BadOrigin};
 use mock::{
-	Event, ExtBuilder, MockPriceSource, Origin, PerpetualsExchange, Runtime, System, Treasury, ALICE, BOB, CHARLIE,
-	GEORGES, CurrencyId::{DOT, LDOT}
+	Event, ExtBuilder, MinimumMargin, MockLender, MockPriceSource, Origin, PerpetualsExchange, Runtime, StorageDeposit, System, Treasury, VolumeBucketLength, WithdrawalPeriod, ALICE, BOB, CHARLIE,
+	GEORGES, CurrencyId::{DOT, KUSD, LDOT}
 };
 
 fn last_event() -> Event {
@@ -44,7 +44,7 @@ fn setup_parameters_works() {
 		MockPriceSource::set_price(DOT, Some(10u128.into()));
 		PerpetualsExchange::on_initialize(1);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128, None));
 
 		assert_noop!(
 			PerpetualsExchange::set_global_params(
@@ -52,42 +52,45 @@ fn setup_parameters_works() {
 				DOT,
 				Change::NewValue(Permill::from_percent(30)),
 				Change::NoChange,
+				Change::NoChange,
 				Change::NoChange
 			),
 			BadOrigin
 		);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 201i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 201i128, None));
 
 		assert_ok!(PerpetualsExchange::set_global_params(
 			Origin::signed(ALICE),
 			DOT,
 			Change::NewValue(Permill::from_percent(30)),
 			Change::NoChange,
+			Change::NoChange,
 			Change::NoChange
 		));
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 401i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 401i128, None));
 
 		assert_ok!(PerpetualsExchange::set_global_params(
 			Origin::signed(ALICE),
 			DOT,
 			Change::NoChange,
 			Change::NoChange,
-			Change::NewValue(Permill::from_percent(2))
+			Change::NewValue(Permill::from_percent(2)),
+			Change::NoChange
 		));
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 410i128),
+			PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 410i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 420i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 420i128, None));
 
 		assert_noop!(
 			PerpetualsExchange::set_global_params(
@@ -95,6 +98,7 @@ fn setup_parameters_works() {
 				DOT,
 				Change::NewValue(Permill::from_percent(1)),
 				Change::NoChange,
+				Change::NoChange,
 				Change::NoChange
 			),
 			crate::Error::<Runtime>::BadIMParameters
@@ -106,6 +110,7 @@ fn setup_parameters_works() {
 				DOT,
 				Change::NoChange,
 				Change::NewValue(Permill::from_percent(30)),
+				Change::NoChange,
 				Change::NoChange
 			),
 			crate::Error::<Runtime>::BadIMParameters
@@ -116,6 +121,7 @@ fn setup_parameters_works() {
 			DOT,
 			Change::NoChange,
 			Change::NewValue(Permill::from_percent(29)),
+			Change::NoChange,
 			Change::NoChange
 		));
 
@@ -132,16 +138,16 @@ fn top_up_collateral_works() {
 		System::reset_events();
 		PerpetualsExchange::on_initialize(1);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 10i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 10i128, None));
 
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 30u128);
 		assert_eq!(balance_of_treasury(), 1u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 30u128);
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 2_000_000_000_000_000_000i128,),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 2_000_000_000_000_000_000i128, None),
 			pallet_balances::Error::<Runtime>::InsufficientBalance,
 		);
 
@@ -163,22 +169,23 @@ fn mint_works() {
 				Origin::signed(ALICE),
 				DOT,
 				2_000_000_000_000_000_000i128,
-				2_000_000_000_000_000_000i128
+				2_000_000_000_000_000_000i128,
+				None
 			),
 			pallet_balances::Error::<Runtime>::InsufficientBalance
 		);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 0u128);
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10i128, 1i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10i128, 1i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
 
 		assert_eq!(
 			last_event(),
-			Event::ecosystem_perpetuals_exchange(crate::Event::BalanceUpdated(ALICE, 100i128))
+			Event::ecosystem_perpetuals_exchange(crate::Event::BalanceUpdated(ALICE, DOT, 100i128))
 		);
 
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 20u128);
@@ -188,31 +195,31 @@ fn mint_works() {
 		);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 20u128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -10i128, 0i128)); // Removes balance so no IM needed
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -10i128, 0i128, None)); // Removes balance so no IM needed
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 19u128); // consumes 1 in fees
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 19u128); // also out of ALICE's margin
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 90i128);
 
 		// Only 10 unit added, so 2.02 IM needed but margin is down 1, so top up by 4
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 20i128, 3i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 20i128, 3i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 20i128, 4i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 20i128, 4i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 22u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 22u128);
 
 		// balance is now -200, so 40 IM needed, 22 already there, so need 18
 		// plus fees of 0.31 rounded up to 1 so 19 needed
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -310i128, 18i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -310i128, 18i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -310i128, 19i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -310i128, 19i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 40u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 40u128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 60u128);
 		assert_eq!(balance_of_treasury(), 5u128);
 		assert_eq!(PerpetualsExchange::margin(&BOB), 20u128);
@@ -227,26 +234,26 @@ fn match_interest_works() {
 
 		PerpetualsExchange::on_initialize(1);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
 
 		PerpetualsExchange::on_initialize(2);
 
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -100i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -50i128, 1i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -50i128, 1i128, None));
 		PerpetualsExchange::on_initialize(3);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -50i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 21i128, None));
 		PerpetualsExchange::on_initialize(4);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 33i128);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 66i128);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -100i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -100i128, 21i128, None));
 		PerpetualsExchange::on_initialize(4);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
 		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 100i128);
@@ -262,14 +269,14 @@ fn redeem_works() {
 		System::reset_events();
 		PerpetualsExchange::on_initialize(1);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 20u128);
 		assert_eq!(balance_of_treasury(), 1u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 20u128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 100i128);
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -1i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -1i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 
@@ -277,26 +284,26 @@ fn redeem_works() {
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 20u128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 100i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 60i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 60i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 79u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 79u128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 200i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, -10i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, -10i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 68u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 68u128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 300i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 13i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 13i128, None));
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 80u128);
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 80u128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 400i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 10i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 11i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, 10i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 11i128, None));
 
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 10i128),
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 10i128, None),
 			crate::Error::<Runtime>::NotEnoughIM
 		);
 	});
@@ -311,10 +318,10 @@ fn liquidate_works() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 20i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 20i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 20i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 20i128, None));
 		PerpetualsExchange::on_initialize(2);
 
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 73i128);
@@ -369,8 +376,8 @@ fn liquidate_works_0_price() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128, None));
 		PerpetualsExchange::match_interest(DOT);
 
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
@@ -403,19 +410,21 @@ fn liquidate_works_complex_2() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128, None));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(CHARLIE),
 			DOT,
 			100i128,
-			4000i128
+			4000i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			DOT,
 			100i128,
-			4000i128
+			4000i128,
+			None
 		));
 		PerpetualsExchange::match_interest(DOT);
 
@@ -469,10 +478,10 @@ fn liquidate_works_complex() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 450i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 400i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 400i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 450i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 400i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 400i128, None));
 		PerpetualsExchange::match_interest(DOT);
 
 		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 73i128);
@@ -536,10 +545,10 @@ fn update_balances_works() {
 		System::reset_events();
 		PerpetualsExchange::on_initialize(1);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 20i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 20i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 20i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -10i128, 20i128, None));
 		PerpetualsExchange::on_initialize(2);
 
 		MockPriceSource::set_price(DOT, Some(2u128.into()));
@@ -560,7 +569,7 @@ fn update_balances_works() {
 		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 55u128);
 		assert_eq!(PerpetualsExchange::margin(&GEORGES), 9u128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 0i128, 120i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 0i128, 120i128, None));
 		assert_eq!(PerpetualsExchange::margin(&BOB), 120u128);
 	});
 }
@@ -574,14 +583,15 @@ fn claim_collateral() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 402i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 402i128, None));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			DOT,
 			-100i128,
-			402i128
+			402i128,
+			None
 		));
 		PerpetualsExchange::match_interest(DOT);
 
@@ -615,9 +625,9 @@ fn claim_collateral() {
 		assert_eq!(PerpetualsExchange::balances(DOT, &GEORGES), -100i128);
 
 		// Claim back collateral
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 0i128, -1600i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 0i128, -1600i128, None));
 		assert_noop!(
-			PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 0i128, -1i128),
+			PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 0i128, -1i128, None),
 			pallet_balances::Error::<Runtime>::InsufficientBalance,
 		);
 	});
@@ -632,14 +642,15 @@ fn claim_collateral_2() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 402i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 402i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 402i128, None));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			DOT,
 			-100i128,
-			402i128
+			402i128,
+			None
 		));
 		PerpetualsExchange::match_interest(DOT);
 
@@ -673,7 +684,7 @@ fn claim_collateral_2() {
 		assert_eq!(PerpetualsExchange::balances(DOT, &GEORGES), -100i128);
 
 		// Claim back collateral
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, -1600i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, -1600i128, None));
 		PerpetualsExchange::match_interest(DOT);
 
 		MockPriceSource::set_price(DOT, Some(10u128.into()));
@@ -695,8 +706,8 @@ fn claim_collateral_2() {
 		assert_eq!(PerpetualsExchange::balances(DOT, &CHARLIE), 0i128);
 		assert_eq!(PerpetualsExchange::balances(DOT, &GEORGES), -100i128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 0i128, -200i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 0i128, -200i128, None));
 	});
 }
 
@@ -714,25 +725,29 @@ fn multiple_assets_works() {
 			Origin::signed(ALICE),
 			DOT,
 			1000i128,
-			10020i128
+			10020i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(ALICE),
 			LDOT,
 			-1000i128,
-			400i128
+			400i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			DOT,
 			-1000i128,
-			4020i128
+			4020i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			LDOT,
 			1000i128,
-			6400i128
+			6400i128,
+			None
 		));
 
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 20000u128);
@@ -740,10 +755,10 @@ fn multiple_assets_works() {
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 10000u128);
 		assert_eq!(PerpetualsExchange::margin(&GEORGES), 10000u128);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -100i128, 0i128)); // Removes balance so no IM needed
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -100i128, 0i128, None)); // Removes balance so no IM needed
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 19998u128); // consumes 2 in fees
 		assert_eq!(PerpetualsExchange::margin(&ALICE), 9998u128); // also out of ALICE's margin
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), LDOT, -100i128, 0i128)); // Removes balance so no IM needed
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), LDOT, -100i128, 0i128, None)); // Removes balance so no IM needed
 		assert_eq!(PerpetualsExchange::total_collateral_balance(), 19958u128); // consumes 40 in fees
 		assert_eq!(PerpetualsExchange::margin(&GEORGES), 9960u128); // also out of GEORGES's margin
 		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 900i128);
@@ -774,6 +789,119 @@ fn multiple_assets_works() {
 	});
 }
 
+#[test]
+fn inverse_assets_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		// A DOT unit pays 1 / P of DOT, so it is worth one unit of margin
+		// whatever the price
+		CollateralParams::<Runtime>::mutate(DOT, |params| params.payoff = Payoff::Inverse);
+		MockPriceSource::set_price(DOT, Some(2u128.into()));
+		MockPriceSource::set_price(LDOT, Some(20u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		PerpetualsExchange::update_margin(LDOT);
+
+		// 20000 units are worth 20000 of margin, so the IM is 4000 and the
+		// fee 20
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 20000i128, 4019i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualsExchange::mint(
+			Origin::signed(ALICE),
+			DOT,
+			20000i128,
+			10020i128,
+			None
+		));
+		assert_ok!(PerpetualsExchange::mint(
+			Origin::signed(ALICE),
+			LDOT,
+			-1000i128,
+			400i128,
+			None
+		));
+		assert_ok!(PerpetualsExchange::mint(
+			Origin::signed(GEORGES),
+			DOT,
+			-20000i128,
+			4020i128,
+			None
+		));
+		assert_ok!(PerpetualsExchange::mint(
+			Origin::signed(GEORGES),
+			LDOT,
+			1000i128,
+			6400i128,
+			None
+		));
+
+		assert_eq!(PerpetualsExchange::total_collateral_balance(), 20000u128);
+		assert_eq!(balance_of_treasury(), 840u128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 10000u128);
+		assert_eq!(PerpetualsExchange::margin(&GEORGES), 10000u128);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -2000i128, 0i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 9998u128); // 2 in fees on a notional of 2000
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), LDOT, -100i128, 0i128, None));
+		assert_eq!(PerpetualsExchange::margin(&GEORGES), 9960u128);
+
+		PerpetualsExchange::match_interest(DOT);
+		PerpetualsExchange::match_interest(LDOT);
+
+		assert_eq!(PerpetualsExchange::inventory(DOT, &GEORGES), -18000i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 18000i128);
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).entry_price, 2u128.into());
+
+		// Longs gain 1 / 2 - 1 / 2.5 = 0.1 DOT per unit, worth 0.25 at 2.5
+		MockPriceSource::set_price(DOT, Some(FixedU128::saturating_from_rational(5, 2)));
+		PerpetualsExchange::update_margin(DOT);
+		PerpetualsExchange::update_margin(LDOT);
+
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 14498u128);
+		assert_eq!(PerpetualsExchange::margin(&GEORGES), 5460u128);
+		assert_eq!(PerpetualsExchange::account_positions(&ALICE)[0].unrealised_pnl, 4500i128);
+
+		// Closing at 2.5 pays 18 in fees and realises the same PnL
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -18000i128, 0i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 14480u128);
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).realised_pnl, 4500i128);
+		assert_eq!(PerpetualsExchange::balances(LDOT, &GEORGES), 900i128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &GEORGES), -20000i128);
+	});
+}
+
+#[test]
+fn inverse_entry_price_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		CollateralParams::<Runtime>::mutate(DOT, |params| params.payoff = Payoff::Inverse);
+		PerpetualsExchange::update_margin(DOT);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, 100i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, -100i128, 100i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		MockPriceSource::set_price(DOT, Some(2u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, 0i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, -100i128, 0i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		// Harmonic mean of 1 and 2, so the PnL is that of each fill:
+		// 100 * (1 - 1 / 2) + 0 DOT, worth 100 at 2 less the rounding of
+		// the entry price
+		assert_eq!(
+			PerpetualsExchange::position(DOT, &BOB).entry_price,
+			FixedU128::saturating_from_rational(4, 3) + FixedU128::from_inner(1)
+		);
+		assert_eq!(PerpetualsExchange::account_positions(&BOB)[0].unrealised_pnl, 99i128);
+	});
+}
+
 #[test]
 fn multiple_assets_works_2() {
 	ExtBuilder::default().build().execute_with(|| {
@@ -784,24 +912,27 @@ fn multiple_assets_works_2() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 900i128, 10020i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 900i128, 10020i128, None));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(ALICE),
 			LDOT,
 			-1000i128,
-			400i128
+			400i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			DOT,
 			-1000i128,
-			4020i128
+			4020i128,
+			None
 		));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			LDOT,
 			900i128,
-			6400i128
+			6400i128,
+			None
 		));
 
 		PerpetualsExchange::match_interest(DOT);
@@ -833,14 +964,15 @@ fn multiple_assets_works_3() {
 		PerpetualsExchange::update_margin(DOT);
 		PerpetualsExchange::update_margin(LDOT);
 
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 432i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, -100i128, 640i128));
-		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -90i128, 402i128));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 432i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, -100i128, 640i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -90i128, 402i128, None));
 		assert_ok!(PerpetualsExchange::mint(
 			Origin::signed(GEORGES),
 			LDOT,
 			100i128,
-			640i128
+			640i128,
+			None
 		));
 
 		PerpetualsExchange::match_interest(DOT);
@@ -857,3 +989,2358 @@ fn multiple_assets_works_3() {
 		assert_eq!(PerpetualsExchange::balances(DOT, &GEORGES), -90i128);
 	});
 }
+
+#[test]
+fn list_and_delist_asset_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+
+		let params = AssetParams {
+			initial_im_ratio: Permill::from_percent(20),
+			liquidation_ratio: Permill::from_percent(10),
+			transaction_fee: Permill::from_parts(1000),
+			..Default::default()
+		};
+
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), KUSD, 100i128, 21i128, None),
+			crate::Error::<Runtime>::PriceNotSet
+		);
+		assert_noop!(
+			PerpetualsExchange::list_asset(Origin::signed(BOB), KUSD, params.clone()),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::list_asset(Origin::signed(ALICE), DOT, params.clone()),
+			crate::Error::<Runtime>::AssetAlreadyListed
+		);
+		assert_noop!(
+			PerpetualsExchange::list_asset(
+				Origin::signed(ALICE),
+				KUSD,
+				AssetParams {
+					initial_im_ratio: Permill::from_percent(10),
+					liquidation_ratio: Permill::from_percent(10),
+					transaction_fee: Permill::from_parts(1000),
+					..Default::default()
+				}
+			),
+			crate::Error::<Runtime>::BadIMParameters
+		);
+
+		assert_ok!(PerpetualsExchange::list_asset(Origin::signed(ALICE), KUSD, params.clone()));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::AssetListed(KUSD, params.clone()))
+		);
+		assert_eq!(PerpetualsExchange::listed_assets(), vec![DOT, LDOT, KUSD]);
+		assert_eq!(PerpetualsExchange::collateral_params(KUSD), params);
+
+		PerpetualsExchange::on_initialize(2);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), KUSD, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), KUSD, -100i128, 21i128, None));
+		PerpetualsExchange::on_initialize(3);
+		assert_eq!(PerpetualsExchange::inventory(KUSD, &ALICE), 100i128);
+		assert_eq!(PerpetualsExchange::inventory(KUSD, &BOB), -100i128);
+
+		assert_noop!(
+			PerpetualsExchange::delist_asset(Origin::signed(BOB), KUSD, 4),
+			BadOrigin
+		);
+		// Two balances and two positions
+		assert_noop!(
+			PerpetualsExchange::delist_asset(Origin::signed(ALICE), KUSD, 3),
+			crate::Error::<Runtime>::TooManyEntries
+		);
+		assert_eq!(
+			PerpetualsExchange::delist_asset(Origin::signed(ALICE), KUSD, 10).map(|info| info.actual_weight),
+			Ok(Some(<() as WeightInfo>::delist_asset(4)))
+		);
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::AssetDelisted(KUSD))
+		);
+		assert_eq!(PerpetualsExchange::listed_assets(), vec![DOT, LDOT]);
+		assert_eq!(PerpetualsExchange::balances(KUSD, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::balances(KUSD, &BOB), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(KUSD, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(KUSD, &BOB), 0i128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 20u128);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 20u128);
+
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), KUSD, 100i128, 21i128, None),
+			crate::Error::<Runtime>::PriceNotSet
+		);
+		assert_noop!(
+			PerpetualsExchange::delist_asset(Origin::signed(ALICE), KUSD, 0),
+			crate::Error::<Runtime>::BadAssetID
+		);
+	});
+}
+
+#[test]
+fn unpriced_asset_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::list_asset(
+			Origin::signed(ALICE),
+			KUSD,
+			AssetParams {
+				initial_im_ratio: Permill::from_percent(20),
+				liquidation_ratio: Permill::from_percent(10),
+				transaction_fee: Permill::from_parts(1000),
+				..Default::default()
+			}
+		));
+		assert_eq!(PerpetualsExchange::price0(KUSD), None);
+
+		// Until the first price of KUSD, accounts without a position in it
+		// still trade, withdraw and get liquidated in other assets
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 30i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 30i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -5i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 24u128);
+		PerpetualsExchange::match_interest(DOT);
+		PerpetualsExchange::liquidate();
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), KUSD, 100i128, 21i128, None),
+			crate::Error::<Runtime>::PriceNotSet
+		);
+	});
+}
+
+#[test]
+fn fee_tiers_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		let tier = FeeTier {
+			min_volume: 1_000,
+			maker_fee: Permill::from_parts(500),
+			taker_fee: Permill::from_parts(800),
+		};
+
+		assert_noop!(
+			PerpetualsExchange::set_fee_tiers(Origin::signed(BOB), vec![tier.clone()]),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::set_fee_tiers(Origin::signed(ALICE), vec![tier.clone(), tier.clone()]),
+			crate::Error::<Runtime>::BadFeeTiers
+		);
+		assert_ok!(PerpetualsExchange::set_fee_tiers(Origin::signed(ALICE), vec![tier.clone()]));
+		assert_eq!(PerpetualsExchange::fee_tiers(), vec![tier]);
+
+		// No volume yet, so the asset's transaction fee applies
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10_000i128, 20_100i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Traded(TradePayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				amount: 10_000,
+				notional: 100_000,
+				price: 10u128.into(),
+				fee: 100,
+				rate: Permill::from_parts(1000),
+				liquidity: Liquidity::Taker,
+			}))));
+		assert_eq!(PerpetualsExchange::rolling_volume(&ALICE), 100_000u128);
+
+		// BOB reduces the imbalance so is a maker, but has no volume yet
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -5_000i128, 10_050i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Traded(TradePayload::V1 {
+				account: BOB,
+				asset: DOT,
+				amount: -5_000,
+				notional: 50_000,
+				price: 10u128.into(),
+				fee: 50,
+				rate: Permill::from_parts(1000),
+				liquidity: Liquidity::Maker,
+			}))));
+
+		// ALICE is now in the first tier
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10_000i128, 20_080i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Traded(TradePayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				amount: 10_000,
+				notional: 100_000,
+				price: 10u128.into(),
+				fee: 80,
+				rate: Permill::from_parts(800),
+				liquidity: Liquidity::Taker,
+			}))));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 40_000u128);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -10_000i128, 0i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Traded(TradePayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				amount: -10_000,
+				notional: 100_000,
+				price: 10u128.into(),
+				fee: 50,
+				rate: Permill::from_parts(500),
+				liquidity: Liquidity::Maker,
+			}))));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 39_950u128);
+		assert_eq!(balance_of_treasury(), 280u128);
+		assert_eq!(PerpetualsExchange::rolling_volume(&ALICE), 300_000u128);
+
+		// Volume rolls out of the window
+		System::set_block_number(40);
+		assert_eq!(PerpetualsExchange::rolling_volume(&ALICE), 0u128);
+
+		// Buckets of 0 blocks are buckets of 1
+		VolumeBucketLength::set(0);
+		assert_eq!(PerpetualsExchange::current_volume_bucket(), 40);
+		assert_eq!(PerpetualsExchange::rolling_volume(&ALICE), 0u128);
+		VolumeBucketLength::set(10);
+	});
+}
+
+#[test]
+fn fee_split_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_fee_split(
+				Origin::signed(BOB),
+				Permill::from_percent(20),
+				Permill::from_percent(30)
+			),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::set_fee_split(
+				Origin::signed(ALICE),
+				Permill::from_percent(70),
+				Permill::from_percent(31)
+			),
+			crate::Error::<Runtime>::BadFeeSplit
+		);
+		assert_ok!(PerpetualsExchange::set_fee_split(
+			Origin::signed(ALICE),
+			Permill::from_percent(20),
+			Permill::from_percent(30)
+		));
+
+		// Fee of 100
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10_000i128, 20_100i128, None));
+		assert_eq!(balance_of_treasury(), 50u128);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&PerpetualsExchange::insurance_account()),
+			20u128
+		);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&PerpetualsExchange::reward_pot_account()),
+			30u128
+		);
+		let distribution = FeeDistribution {
+			treasury: 50,
+			insurance: 20,
+			rewards: 30,
+			referrals: 0,
+		};
+		assert_eq!(PerpetualsExchange::block_fees(), distribution);
+
+		PerpetualsExchange::on_finalize(1);
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::FeesDistributed(distribution))
+		);
+
+		PerpetualsExchange::on_initialize(2);
+		assert_eq!(PerpetualsExchange::block_fees(), FeeDistribution::default());
+	});
+}
+
+#[test]
+fn referral_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_referral_params(
+				Origin::signed(BOB),
+				Permill::from_percent(10),
+				Permill::from_percent(5)
+			),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::set_referral_params(
+				Origin::signed(ALICE),
+				Permill::from_percent(60),
+				Permill::from_percent(41)
+			),
+			crate::Error::<Runtime>::BadReferralParams
+		);
+		assert_ok!(PerpetualsExchange::set_referral_params(
+			Origin::signed(ALICE),
+			Permill::from_percent(10),
+			Permill::from_percent(5)
+		));
+
+		assert_noop!(
+			PerpetualsExchange::register_referrer(Origin::signed(BOB), BOB),
+			crate::Error::<Runtime>::SelfReferral
+		);
+		assert_ok!(PerpetualsExchange::register_referrer(Origin::signed(BOB), CHARLIE));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::ReferrerRegistered(BOB, CHARLIE))
+		);
+		assert_eq!(PerpetualsExchange::referrer_of(&BOB), Some(CHARLIE));
+		assert_noop!(
+			PerpetualsExchange::register_referrer(Origin::signed(BOB), GEORGES),
+			crate::Error::<Runtime>::ReferrerAlreadySet
+		);
+		assert_noop!(
+			PerpetualsExchange::register_referrer(Origin::signed(CHARLIE), BOB),
+			crate::Error::<Runtime>::SelfReferral
+		);
+
+		// Fee of 100, 10 to CHARLIE and 5 back to BOB
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 10_000i128, 20_100i128, None));
+		assert_eq!(balance_of_treasury(), 85u128);
+		assert_eq!(PerpetualsExchange::pending_rewards(&CHARLIE), 10u128);
+		assert_eq!(PerpetualsExchange::pending_rewards(&BOB), 5u128);
+		assert_eq!(PerpetualsExchange::block_fees().referrals, 15u128);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&PerpetualsExchange::referral_pot_account()),
+			15u128
+		);
+
+		// ALICE has no referrer so the treasury gets all of the fee
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -10_000i128, 20_100i128, None));
+		assert_eq!(balance_of_treasury(), 185u128);
+
+		assert_ok!(PerpetualsExchange::claim_rewards(Origin::signed(CHARLIE)));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::RewardsClaimed(CHARLIE, 10u128))
+		);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&CHARLIE),
+			1_000_000_000_000_000_010u128
+		);
+		assert_ok!(PerpetualsExchange::claim_rewards(Origin::signed(BOB)));
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&PerpetualsExchange::referral_pot_account()),
+			0u128
+		);
+		assert_noop!(
+			PerpetualsExchange::claim_rewards(Origin::signed(BOB)),
+			crate::Error::<Runtime>::NoRewards
+		);
+	});
+}
+
+#[test]
+fn vault_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_vault_risk_limit(Origin::signed(BOB), DOT, 50u128),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::set_vault_risk_limit(Origin::signed(ALICE), KUSD, 50u128),
+			crate::Error::<Runtime>::BadAssetID
+		);
+		assert_ok!(PerpetualsExchange::set_vault_risk_limit(Origin::signed(ALICE), DOT, 50u128));
+
+		assert_ok!(PerpetualsExchange::deposit_to_vault(Origin::signed(BOB), 500u128));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::VaultDeposited(BOB, 500u128, 500u128))
+		);
+		let vault = PerpetualsExchange::vault_account();
+		assert_eq!(PerpetualsExchange::margin(&vault), 500u128);
+		assert_eq!(PerpetualsExchange::vault_shares(&BOB), 500u128);
+		assert_eq!(PerpetualsExchange::total_vault_shares(), 500u128);
+
+		// No shorts, the vault takes the other side up to its risk limit
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::balances(DOT, &vault), -50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &vault), -50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
+
+		assert_ok!(PerpetualsExchange::set_vault_risk_limit(Origin::signed(ALICE), DOT, 1_000u128));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &vault), -100i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+
+		MockPriceSource::set_price(DOT, Some(2u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_eq!(PerpetualsExchange::margin(&vault), 400u128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 120u128);
+
+		assert_noop!(
+			PerpetualsExchange::request_vault_withdrawal(Origin::signed(BOB), 501u128),
+			crate::Error::<Runtime>::NotEnoughShares
+		);
+		assert_noop!(
+			PerpetualsExchange::withdraw_from_vault(Origin::signed(BOB)),
+			crate::Error::<Runtime>::NoPendingWithdrawal
+		);
+		assert_ok!(PerpetualsExchange::request_vault_withdrawal(Origin::signed(BOB), 500u128));
+		assert_eq!(PerpetualsExchange::vault_withdrawal(&BOB), Some((500u128, 6)));
+		assert_noop!(
+			PerpetualsExchange::withdraw_from_vault(Origin::signed(BOB)),
+			crate::Error::<Runtime>::VaultCooldownNotOver
+		);
+
+		// The vault needs 40 of IM for its position
+		System::set_block_number(6);
+		assert_noop!(
+			PerpetualsExchange::withdraw_from_vault(Origin::signed(BOB)),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+
+		assert_ok!(PerpetualsExchange::request_vault_withdrawal(Origin::signed(BOB), 250u128));
+		System::set_block_number(11);
+		assert_ok!(PerpetualsExchange::withdraw_from_vault(Origin::signed(BOB)));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::VaultWithdrawn(BOB, 200u128, 250u128))
+		);
+		assert_eq!(PerpetualsExchange::margin(&vault), 200u128);
+		assert_eq!(PerpetualsExchange::vault_shares(&BOB), 250u128);
+		assert_eq!(PerpetualsExchange::total_vault_shares(), 250u128);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&BOB),
+			999_999_999_999_999_700u128
+		);
+	});
+}
+
+#[test]
+fn vault_collects_rewards() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::set_fee_split(
+			Origin::signed(ALICE),
+			Permill::zero(),
+			Permill::from_percent(30)
+		));
+		assert_ok!(PerpetualsExchange::deposit_to_vault(Origin::signed(BOB), 1_000u128));
+
+		// Fee of 100, 30 of which to the vault
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10_000i128, 20_100i128, None));
+		PerpetualsExchange::on_finalize(1);
+
+		let vault = PerpetualsExchange::vault_account();
+		assert_eq!(PerpetualsExchange::margin(&vault), 1_030u128);
+		assert_eq!(
+			PerpetualsExchange::total_treasury_balance(&PerpetualsExchange::reward_pot_account()),
+			0u128
+		);
+		assert_eq!(PerpetualsExchange::total_collateral_balance(), 21_030u128);
+
+		// New shares are priced at the vault's value
+		assert_ok!(PerpetualsExchange::deposit_to_vault(Origin::signed(CHARLIE), 103u128));
+		assert_eq!(PerpetualsExchange::vault_shares(&CHARLIE), 100u128);
+	});
+}
+
+#[test]
+fn largest_remainder_matching_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_matching_policy(Origin::signed(BOB), DOT, MatchingPolicy::LargestRemainder),
+			BadOrigin
+		);
+		assert_ok!(PerpetualsExchange::set_matching_policy(
+			Origin::signed(ALICE),
+			DOT,
+			MatchingPolicy::LargestRemainder
+		));
+		assert_eq!(PerpetualsExchange::matching_policy(DOT), MatchingPolicy::LargestRemainder);
+		assert_eq!(PerpetualsExchange::matching_policy(LDOT), MatchingPolicy::ProRata);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 50i128, 11i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		// 33.3 and 66.7, the dust goes to CHARLIE
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 33i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 67i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -100i128);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -7i128, 3i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		// 35.67 and 71.33, the dust goes to ALICE
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 36i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 71i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -100i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &GEORGES), -7i128);
+		assert_eq!(
+			Inventory::<Runtime>::iter_prefix_values(DOT).fold(0i128, |acc, i| acc + i),
+			0i128
+		);
+	});
+}
+
+#[test]
+fn largest_remainder_large_balances_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		// Thousands of tokens at 18 decimals, balance times fill is past u128
+		let e = 1_000_000_000_000_000_000u128;
+		let excess = vec![
+			(ALICE, 7_000 * e + 1),
+			(BOB, 11_000 * e + 3),
+			(CHARLIE, 13_000 * e + 7),
+		];
+		let oversubscribed = 31_000 * e + 11;
+		let filled = 17_000 * e + 5;
+
+		// Remainders of 30.8, 10.6 and 20.6 thousand tokens, the 2 units of
+		// dust go to ALICE and CHARLIE
+		let fills = PerpetualsExchange::fill_largest_remainder(&excess, filled, oversubscribed);
+		assert_eq!(
+			fills,
+			vec![
+				3_838_709_677_419_354_838_710u128,
+				6_032_258_064_516_129_032_259u128,
+				7_129_032_258_064_516_129_036u128,
+			]
+		);
+		assert_eq!(fills.iter().sum::<u128>(), filled);
+
+		assert_eq!(
+			PerpetualsExchange::mul_div_rem(13_000 * e + 7, filled, oversubscribed),
+			Some((
+				7_129_032_258_064_516_129_035u128,
+				20_580_645_161_290_322_580_650u128
+			))
+		);
+		assert_eq!(PerpetualsExchange::mul_div_rem(u128::MAX, u128::MAX, 7), None);
+		assert_eq!(PerpetualsExchange::mul_div_rem(1, 1, 0), None);
+	});
+}
+
+#[test]
+fn fifo_matching_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::set_matching_policy(
+			Origin::signed(ALICE),
+			DOT,
+			MatchingPolicy::Fifo
+		));
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 50i128, 11i128, None));
+		System::set_block_number(2);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 21i128, None));
+		System::set_block_number(3);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -80i128, 17i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 30i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -80i128);
+
+		// Increasing a position sends it to the back of the queue
+		System::set_block_number(4);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10i128, 3i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 80i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -80i128);
+
+		// Reducing does not
+		System::set_block_number(5);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, -10i128, 0i128, None));
+		PerpetualsExchange::match_interest(DOT);
+
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 80i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -80i128);
+
+		// Within a block, positions are filled in the order they were
+		// increased
+		System::set_block_number(6);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 30i128, 7i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10i128, 3i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -30i128, 7i128, None));
+		assert!(PerpetualsExchange::order_sequence(DOT, &GEORGES) < PerpetualsExchange::order_sequence(DOT, &ALICE));
+		PerpetualsExchange::match_interest(DOT);
+
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 90i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &GEORGES), 20i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -110i128);
+	});
+}
+
+#[test]
+fn incremental_matching_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		assert!(PerpetualsExchange::needs_matching(DOT));
+		assert_eq!(
+			PerpetualsExchange::open_interest(DOT),
+			OpenInterest {
+				longs: 100,
+				shorts: 100
+			}
+		);
+		PerpetualsExchange::match_interest(DOT);
+		assert!(!PerpetualsExchange::needs_matching(DOT));
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -100i128);
+
+		// Nothing changed, so nothing is recomputed
+		Inventory::<Runtime>::insert(DOT, BOB, -7i128);
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -7i128);
+
+		// The ratio did not move, so only the changed accounts are recomputed
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 11i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -50i128, 11i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -7i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &GEORGES), -50i128);
+
+		// The ratio moved, so everything is recomputed
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -50i128, 11i128, None));
+		assert_eq!(
+			PerpetualsExchange::open_interest(DOT),
+			OpenInterest {
+				longs: 150,
+				shorts: 200
+			}
+		);
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -75i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &GEORGES), -75i128);
+	});
+}
+
+#[test]
+fn position_pnl_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 201i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 1001i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(
+			PerpetualsExchange::position(DOT, &ALICE),
+			PositionInfo {
+				entry_price: 10u128.into(),
+				realised_pnl: 0
+			}
+		);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::PositionUpdated(
+				BOB,
+				DOT,
+				-100i128,
+				10u128.into(),
+				0i128
+			))));
+
+		// Half of the position is closed at a profit of 2 per unit
+		MockPriceSource::set_price(DOT, Some(12u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -50i128, 0i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 50i128, 0i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(
+			PerpetualsExchange::position(DOT, &ALICE),
+			PositionInfo {
+				entry_price: 10u128.into(),
+				realised_pnl: 100
+			}
+		);
+		assert_eq!(
+			PerpetualsExchange::position(DOT, &BOB),
+			PositionInfo {
+				entry_price: 10u128.into(),
+				realised_pnl: -100
+			}
+		);
+		assert_eq!(
+			PerpetualsExchange::account_positions(&ALICE),
+			vec![AccountPosition {
+				asset: DOT,
+				balance: 50,
+				inventory: 50,
+				entry_price: 10u128.into(),
+				realised_pnl: 100,
+				unrealised_pnl: 100,
+				leverage: None,
+			}]
+		);
+
+		// Increasing the position averages the entry price
+		MockPriceSource::set_price(DOT, Some(14u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 50i128, 0i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -50i128, 0i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).entry_price, 12u128.into());
+		assert_eq!(PerpetualsExchange::position(DOT, &BOB).entry_price, 12u128.into());
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).realised_pnl, 100i128);
+	});
+}
+
+#[test]
+fn events_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::PriceUpdated(PricePayload::V1 {
+				asset: DOT,
+				old_price: None,
+				new_price: 1u128.into(),
+			}))));
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Traded(TradePayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				amount: 100,
+				notional: 100,
+				price: 1u128.into(),
+				fee: 1,
+				rate: Permill::from_parts(1000),
+				liquidity: Liquidity::Taker,
+			}))));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::CollateralUpdated(ALICE, DOT, 20i128, 20u128))));
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Matched(MatchPayload::V1 {
+				asset: DOT,
+				longs: 100,
+				shorts: 100,
+				matched: 100,
+				price: 1u128.into(),
+			}))));
+
+		let price = FixedU128::saturating_from_rational(85, 100);
+		MockPriceSource::set_price(DOT, Some(price));
+		PerpetualsExchange::update_margin(DOT);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::MarginMarked(MarginMarkPayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				inventory: 100,
+				old_price: 1u128.into(),
+				new_price: price,
+				pnl: -15,
+				margin: 5,
+			}))));
+
+		PerpetualsExchange::on_initialize(2);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::Liquidated(LiquidationPayload::V1 {
+				account: ALICE,
+				asset: DOT,
+				balance: 100,
+				inventory: 100,
+				price,
+				margin: 5,
+			}))));
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 0i128);
+	});
+}
+
+#[test]
+fn migrate_to_v2_works() {
+	// Genesis of the first release, which had no `ListedAssets`
+	let mut t = frame_system::GenesisConfig::default()
+		.build_storage::<Runtime>()
+		.unwrap();
+	pallet_balances::GenesisConfig::<Runtime> {
+		balances: vec![
+			(ALICE, 1_000_000_000_000_000_000u128),
+			(BOB, 1_000_000_000_000_000_000u128),
+		],
+	}
+	.assimilate_storage(&mut t)
+	.unwrap();
+
+	sp_io::TestExternalities::from(t).execute_with(|| {
+		use frame_support::storage::unhashed;
+		use migrations::v2::OldAssetParams;
+
+		assert!(PerpetualsExchange::listed_assets().is_empty());
+		assert_eq!(StorageVersion::<Runtime>::get(), Releases::V1_0_0);
+
+		// The V1 layout, with zero balances kept and no open interest. KUSD
+		// only has a price, so traded with the default params.
+		for (currency_id, fee) in vec![(DOT, 1000), (LDOT, 20000)] {
+			unhashed::put(
+				&CollateralParams::<Runtime>::hashed_key_for(currency_id),
+				&OldAssetParams {
+					initial_im_ratio: Permill::from_percent(20),
+					liquidation_ratio: Permill::from_percent(10),
+					transaction_fee: Permill::from_parts(fee),
+				},
+			);
+			Price0::<Runtime>::insert(currency_id, FixedU128::one());
+		}
+		Price0::<Runtime>::insert(KUSD, FixedU128::one());
+		for (account, balance) in vec![(ALICE, 100i128), (BOB, -100i128), (CHARLIE, 0i128)] {
+			Balances::<Runtime>::insert(DOT, account, balance);
+			Inventory::<Runtime>::insert(DOT, account, balance);
+		}
+		Margin::<Runtime>::insert(ALICE, 20u128);
+		Margin::<Runtime>::insert(BOB, 20u128);
+
+		assert_ok!(migrations::pre_upgrade::<Runtime>());
+		migrations::migrate::<Runtime>();
+		assert_ok!(migrations::post_upgrade::<Runtime>());
+
+		assert_eq!(StorageVersion::<Runtime>::get(), Releases::V4_0_0);
+		let mut listed = PerpetualsExchange::listed_assets();
+		listed.sort();
+		assert_eq!(listed, vec![DOT, LDOT, KUSD]);
+		assert_eq!(
+			PerpetualsExchange::collateral_params(DOT),
+			AssetParams {
+				initial_im_ratio: Permill::from_percent(20),
+				liquidation_ratio: Permill::from_percent(10),
+				transaction_fee: Permill::from_parts(1000),
+				matching_policy: MatchingPolicy::ProRata,
+				vault_risk_limit: 0,
+				payoff: Payoff::Linear,
+				brackets: vec![],
+			}
+		);
+		assert_eq!(PerpetualsExchange::collateral_params(LDOT).transaction_fee, Permill::from_parts(20000));
+		assert_eq!(PerpetualsExchange::collateral_params(KUSD).initial_im_ratio, Permill::zero());
+		assert!(CollateralParams::<Runtime>::contains_key(KUSD));
+
+		// Balances are tracked as later releases expect
+		assert_eq!(
+			PerpetualsExchange::open_interest(DOT),
+			OpenInterest { longs: 100, shorts: 100 }
+		);
+		assert!(!Balances::<Runtime>::contains_key(DOT, &CHARLIE));
+		assert!(!Inventory::<Runtime>::contains_key(DOT, &CHARLIE));
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).entry_price, FixedU128::one());
+		assert!(!PerpetualsExchange::needs_matching(DOT));
+
+		// Running it again is a no-op
+		let params = PerpetualsExchange::collateral_params(DOT);
+		migrations::migrate::<Runtime>();
+		assert_eq!(PerpetualsExchange::collateral_params(DOT), params);
+
+		// Trading still works on the migrated state
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_eq!(
+			PerpetualsExchange::open_interest(DOT),
+			OpenInterest { longs: 200, shorts: 100 }
+		);
+	});
+}
+
+#[test]
+fn storage_cleanup_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		PerpetualsExchange::on_initialize(1);
+		MinimumMargin::set(5);
+		StorageDeposit::set(10);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None));
+		assert_eq!(PerpetualsExchange::storage_deposit(&ALICE), Some(10u128));
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&ALICE), 10u128);
+
+		// Closing the position removes its entry, the margin stays
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -100i128, 0i128, None));
+		assert!(!Balances::<Runtime>::contains_key(DOT, &ALICE));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 19u128);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -15i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 4u128);
+
+		// Withdrawing everything closes the account straight away
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 21i128, None));
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&BOB), 10u128);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, -19i128, None));
+		assert!(!Margin::<Runtime>::contains_key(&BOB));
+		assert_eq!(PerpetualsExchange::storage_deposit(&BOB), None);
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&BOB), 0u128);
+
+		// The dust left by ALICE goes to the treasury
+		let treasury = balance_of_treasury();
+		PerpetualsExchange::on_initialize(2);
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::DustSwept(ALICE, 4u128))
+		);
+		assert_eq!(balance_of_treasury(), treasury + 4);
+		assert!(!Margin::<Runtime>::contains_key(&ALICE));
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&ALICE), 0u128);
+
+		// Liquidated positions are removed rather than set to 0
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, -100i128, 21i128, None));
+		PerpetualsExchange::on_initialize(3);
+		MockPriceSource::set_price(DOT, Some(FixedU128::saturating_from_rational(85, 100)));
+		PerpetualsExchange::on_initialize(4);
+		assert!(!Balances::<Runtime>::contains_key(DOT, &CHARLIE));
+		assert!(!Inventory::<Runtime>::contains_key(DOT, &CHARLIE));
+		assert!(Inventory::<Runtime>::contains_key(DOT, &GEORGES));
+	});
+}
+
+#[test]
+fn quarantine_conserves_margin_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 300i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 600i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		let total_margin = || PerpetualsExchange::margin(&ALICE) + PerpetualsExchange::margin(&BOB);
+		assert_eq!(total_margin(), 898u128);
+
+		// ALICE is not marked while quarantined, BOB still pays her gain
+		PerpetualsExchange::quarantine(&ALICE, None);
+		assert_eq!(
+			PerpetualsExchange::quarantine_price(&ALICE, DOT),
+			Some(10u128.into())
+		);
+		MockPriceSource::set_price(DOT, Some(12u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 299u128);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 399u128);
+
+		// Her inventory is marked before it is rematched, 1 goes in fees
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 50i128, 0i128, None));
+		PerpetualsExchange::match_interest(DOT);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 499u128);
+		assert_eq!(
+			PerpetualsExchange::quarantine_price(&ALICE, DOT),
+			Some(12u128.into())
+		);
+		assert_eq!(total_margin(), 897u128);
+
+		// And marked to the current price on release
+		MockPriceSource::set_price(DOT, Some(11u128.into()));
+		PerpetualsExchange::update_margin(DOT);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 448u128);
+		assert_ok!(PerpetualsExchange::release_account(Origin::signed(ALICE), ALICE));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 449u128);
+		assert_eq!(PerpetualsExchange::quarantine_price(&ALICE, DOT), None);
+		assert_eq!(total_margin(), 897u128);
+	});
+}
+
+#[test]
+fn quarantine_and_halt_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+
+		// The margin of ALICE cannot be checked
+		Inventory::<Runtime>::insert(DOT, ALICE, i128::MIN);
+		Margin::<Runtime>::insert(ALICE, 10u128);
+		PerpetualsExchange::on_initialize(1);
+		assert!(PerpetualsExchange::quarantined(&ALICE));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::AccountQuarantined(ALICE))
+		);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 21i128, None),
+			crate::Error::<Runtime>::AccountIsQuarantined
+		);
+
+		Inventory::<Runtime>::remove(DOT, ALICE);
+		assert_noop!(
+			PerpetualsExchange::release_account(Origin::signed(BOB), ALICE),
+			BadOrigin
+		);
+		assert_ok!(PerpetualsExchange::release_account(Origin::signed(ALICE), ALICE));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::AccountReleased(ALICE))
+		);
+		assert_noop!(
+			PerpetualsExchange::release_account(Origin::signed(ALICE), ALICE),
+			crate::Error::<Runtime>::AccountNotQuarantined
+		);
+		PerpetualsExchange::on_initialize(2);
+		assert!(!PerpetualsExchange::quarantined(&ALICE));
+
+		// The interest of DOT cannot be matched
+		Balances::<Runtime>::insert(DOT, CHARLIE, 10i128);
+		Balances::<Runtime>::insert(DOT, BOB, i128::MIN);
+		OpenInterests::<Runtime>::insert(
+			DOT,
+			OpenInterest {
+				longs: 10,
+				shorts: u128::MAX,
+			},
+		);
+		ChangedAccounts::<Runtime>::insert(DOT, BOB, ());
+		NeedsMatching::<Runtime>::insert(DOT, true);
+		PerpetualsExchange::match_interest(DOT);
+		assert!(PerpetualsExchange::market_halted(DOT));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::MarketHalted(DOT))
+		);
+		assert!(PerpetualsExchange::needs_matching(DOT));
+		assert!(ChangedAccounts::<Runtime>::contains_key(DOT, BOB));
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 0i128);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(GEORGES), DOT, 100i128, 21i128, None),
+			crate::Error::<Runtime>::MarketIsHalted
+		);
+
+		// Halted markets are left alone by the hooks
+		System::reset_events();
+		PerpetualsExchange::on_initialize(3);
+		assert!(!System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::MarketHalted(DOT))));
+
+		Balances::<Runtime>::insert(DOT, BOB, -10i128);
+		OpenInterests::<Runtime>::insert(DOT, OpenInterest { longs: 10, shorts: 10 });
+		assert_noop!(
+			PerpetualsExchange::resume_market(Origin::signed(BOB), DOT),
+			BadOrigin
+		);
+		assert_ok!(PerpetualsExchange::resume_market(Origin::signed(ALICE), DOT));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::MarketResumed(DOT))
+		);
+		assert_noop!(
+			PerpetualsExchange::resume_market(Origin::signed(ALICE), DOT),
+			crate::Error::<Runtime>::MarketNotHalted
+		);
+		PerpetualsExchange::on_initialize(4);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 10i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &BOB), -10i128);
+	});
+}
+
+#[test]
+fn on_initialize_weight_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		let idle = PerpetualsExchange::on_initialize(1);
+
+		// Marking, matching and liquidation are charged per account
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 41i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 41i128, None));
+		MockPriceSource::set_price(DOT, Some(FixedU128::saturating_from_rational(11, 10)));
+		let busy = PerpetualsExchange::on_initialize(2);
+		let accounts = <Runtime as Config>::WeightInfo::mark_to(2) - <Runtime as Config>::WeightInfo::mark_to(0)
+			+ <Runtime as Config>::WeightInfo::match_interest(2)
+			- <Runtime as Config>::WeightInfo::match_interest(0)
+			+ <Runtime as Config>::WeightInfo::liquidate(4)
+			- <Runtime as Config>::WeightInfo::liquidate(0);
+		assert!(busy >= idle + accounts);
+	});
+}
+
+/// Deterministic pseudo random numbers, biased towards edge cases
+struct Lcg(u64);
+
+impl Lcg {
+	fn next(&mut self) -> u64 {
+		self.0 = self
+			.0
+			.wrapping_mul(6_364_136_223_846_793_005)
+			.wrapping_add(1_442_695_040_888_963_407);
+		self.0 >> 11
+	}
+
+	fn balance(&mut self) -> u128 {
+		match self.next() % 4 {
+			0 => [0, 1, u128::MAX, i128::MAX as u128][(self.next() % 4) as usize],
+			1 => (self.next() % 1_000) as u128,
+			2 => ((self.next() as u128) << 64) | self.next() as u128,
+			_ => self.next() as u128,
+		}
+	}
+
+	fn amount(&mut self) -> i128 {
+		match self.next() % 4 {
+			0 => [0, -1, i128::MIN, i128::MAX][(self.next() % 4) as usize],
+			1 => (self.next() % 1_000) as i128 - 500,
+			_ => self.balance() as i128,
+		}
+	}
+
+	fn price(&mut self) -> Option<FixedU128> {
+		match self.next() % 8 {
+			0 => None,
+			1 => Some(FixedU128::zero()),
+			_ => Some(FixedU128::from_inner(self.balance())),
+		}
+	}
+}
+
+#[test]
+fn hooks_never_panic() {
+	let mut rng = Lcg(42);
+	for _ in 0..200 {
+		ExtBuilder::default().build().execute_with(|| {
+			let accounts = vec![ALICE, BOB, CHARLIE, GEORGES, PerpetualsExchange::vault_account()];
+			let policies = [MatchingPolicy::ProRata, MatchingPolicy::LargestRemainder, MatchingPolicy::Fifo];
+			for currency_id in vec![DOT, LDOT] {
+				for account in accounts.iter() {
+					Balances::<Runtime>::insert(currency_id, account, rng.amount());
+					Inventory::<Runtime>::insert(currency_id, account, rng.amount());
+					OrderBlock::<Runtime>::insert(currency_id, account, rng.next() % 10);
+					OrderSequence::<Runtime>::insert(currency_id, account, rng.next() % 4);
+					if rng.next() % 2 == 0 {
+						ChangedAccounts::<Runtime>::insert(currency_id, account, ());
+					}
+					if let (0, Some(price)) = (rng.next() % 4, rng.price()) {
+						Quarantined::<Runtime>::insert(account, true);
+						QuarantinePrices::<Runtime>::insert(account, currency_id, price);
+					}
+				}
+				for order_id in 0..rng.next() % 4 {
+					if let Some(trigger_price) = rng.price() {
+						TriggerOrders::<Runtime>::insert(
+							currency_id,
+							order_id,
+							TriggerOrder {
+								account: accounts[(rng.next() % 4) as usize],
+								trigger_price,
+								direction: [TriggerDirection::Above, TriggerDirection::Below]
+									[(rng.next() % 2) as usize],
+								amount: rng.amount(),
+								reduce_only: rng.next() % 2 == 0,
+								deposit: 0,
+							},
+						);
+					}
+				}
+				if rng.next() % 2 == 0 {
+					Expiries::<Runtime>::insert(currency_id, (rng.next() % 6, None));
+					if let Some(price) = rng.price() {
+						SettlementSamples::<Runtime>::insert(currency_id, (price, (rng.next() % 3) as u32, 0));
+					}
+				}
+				OpenInterests::<Runtime>::insert(
+					currency_id,
+					OpenInterest {
+						longs: rng.balance(),
+						shorts: rng.balance(),
+					},
+				);
+				LastMatch::<Runtime>::insert(
+					currency_id,
+					OpenInterest {
+						longs: rng.balance(),
+						shorts: rng.balance(),
+					},
+				);
+				NeedsMatching::<Runtime>::insert(currency_id, true);
+				if let Some(price) = rng.price() {
+					Price0::<Runtime>::insert(currency_id, price);
+				}
+				let payoff = match (rng.next() % 4, rng.price()) {
+					(0, _) => Payoff::Inverse,
+					(1, _) => Payoff::Quadratic,
+					(2, Some(cap)) => Payoff::Capped { cap },
+					_ => Payoff::Linear,
+				};
+				let brackets = (0..rng.next() % 3)
+					.map(|_| RiskBracket {
+						min_notional: rng.balance(),
+						initial_im_ratio: Permill::from_parts((rng.next() % 1_000_001) as u32),
+						liquidation_ratio: Permill::from_parts((rng.next() % 1_000_001) as u32),
+					})
+					.collect();
+				CollateralParams::<Runtime>::mutate(currency_id, |params| {
+					params.matching_policy = policies[(rng.next() % 3) as usize];
+					params.vault_risk_limit = rng.balance();
+					params.payoff = payoff;
+					params.brackets = brackets;
+				});
+			}
+			if rng.next() % 2 == 0 {
+				OffsetGroups::<Runtime>::put(vec![OffsetGroup {
+					assets: vec![DOT, LDOT],
+					offset: Permill::from_parts((rng.next() % 1_000_001) as u32),
+				}]);
+			}
+			for account in accounts.iter() {
+				Margin::<Runtime>::insert(account, rng.balance());
+			}
+			TotalVaultShares::<Runtime>::put(rng.balance());
+
+			for block in 1..4 {
+				MockPriceSource::set_price(DOT, rng.price());
+				MockPriceSource::set_price(LDOT, rng.price());
+				System::set_block_number(block);
+				PerpetualsExchange::on_initialize(block);
+				PerpetualsExchange::on_finalize(block);
+			}
+		});
+	}
+}
+
+#[test]
+fn checked_arithmetic_boundaries_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		let third = FixedU128::saturating_from_rational(1, 3);
+
+		// Rounded up, unless exact
+		assert_eq!(payoff::mul_int_ceil(third, 1), Some(1));
+		assert_eq!(payoff::mul_int_ceil(third, 3), Some(1));
+		assert_eq!(payoff::mul_int_ceil(FixedU128::one(), u128::MAX), Some(u128::MAX));
+		assert_eq!(
+			payoff::mul_int_ceil(FixedU128::from_inner(1), u128::MAX),
+			Some(u128::MAX / 1_000_000_000_000_000_000 + 1)
+		);
+		assert_eq!(payoff::mul_int_ceil(2u128.into(), u128::MAX), None);
+
+		// Gains are rounded down, losses up
+		assert_eq!(PerpetualsExchange::mark_pnl(third, 1, true), Some(0));
+		assert_eq!(PerpetualsExchange::mark_pnl(third, 1, false), Some(-1));
+		assert_eq!(PerpetualsExchange::mark_pnl(third, -1, false), Some(0));
+		assert_eq!(PerpetualsExchange::mark_pnl(third, -1, true), Some(-1));
+		assert_eq!(PerpetualsExchange::mark_pnl(FixedU128::one(), i128::MAX, true), Some(i128::MAX));
+		assert_eq!(PerpetualsExchange::mark_pnl(FixedU128::one(), -i128::MAX, true), Some(-i128::MAX));
+		assert_eq!(PerpetualsExchange::mark_pnl(2u128.into(), i128::MAX, true), None);
+		assert_eq!(PerpetualsExchange::mark_pnl(FixedU128::one(), i128::MIN, true), None);
+
+		// The IM is rounded up
+		Price0::<Runtime>::insert(DOT, third);
+		Price0::<Runtime>::insert(LDOT, FixedU128::one());
+		assert_eq!(PerpetualsExchange::initial_margin(&ALICE, Some((DOT, 1))).ok(), Some(1));
+		assert!(PerpetualsExchange::initial_margin(&ALICE, Some((DOT, i128::MIN))).is_err());
+
+		// Marking an account whose PnL or margin overflows quarantines it and
+		// leaves its margin untouched
+		Inventory::<Runtime>::insert(DOT, ALICE, i128::MAX);
+		Margin::<Runtime>::insert(ALICE, 10u128);
+		Inventory::<Runtime>::insert(DOT, BOB, 1i128);
+		Margin::<Runtime>::insert(BOB, u128::MAX);
+		Inventory::<Runtime>::insert(DOT, CHARLIE, -3i128);
+		Margin::<Runtime>::insert(CHARLIE, 10u128);
+		MockPriceSource::set_price(DOT, Some(FixedU128::saturating_from_rational(7, 3)));
+		PerpetualsExchange::update_margin(DOT);
+		assert!(PerpetualsExchange::quarantined(&ALICE));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 10u128);
+		assert!(PerpetualsExchange::quarantined(&BOB));
+		assert_eq!(PerpetualsExchange::margin(&BOB), u128::MAX);
+		// A short of 3 loses 3 * (7/3 - 1/3) = 6, rounded up
+		assert!(!PerpetualsExchange::quarantined(&CHARLIE));
+		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 4u128);
+
+		// They are marked from the price before the overflow on release
+		assert_eq!(PerpetualsExchange::quarantine_price(&ALICE, DOT), Some(third));
+		assert_noop!(
+			PerpetualsExchange::release_account(Origin::signed(ALICE), BOB),
+			crate::Error::<Runtime>::Overflow
+		);
+		Inventory::<Runtime>::insert(DOT, ALICE, 1i128);
+		assert_ok!(PerpetualsExchange::release_account(Origin::signed(ALICE), ALICE));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 12u128);
+	});
+}
+
+#[test]
+fn migrate_to_v3_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		use frame_support::storage::unhashed;
+		use migrations::v3::OldAssetParams;
+
+		StorageVersion::<Runtime>::put(Releases::V2_0_0);
+		unhashed::put(
+			&CollateralParams::<Runtime>::hashed_key_for(DOT),
+			&OldAssetParams {
+				initial_im_ratio: Permill::from_percent(20),
+				liquidation_ratio: Permill::from_percent(10),
+				transaction_fee: Permill::from_parts(1000),
+				matching_policy: MatchingPolicy::LargestRemainder,
+				vault_risk_limit: 50,
+			},
+		);
+		unhashed::put(
+			&CollateralParams::<Runtime>::hashed_key_for(LDOT),
+			&OldAssetParams {
+				initial_im_ratio: Permill::from_percent(30),
+				liquidation_ratio: Permill::from_percent(10),
+				transaction_fee: Permill::from_parts(20000),
+				matching_policy: MatchingPolicy::ProRata,
+				vault_risk_limit: 0,
+			},
+		);
+
+		assert_ok!(migrations::pre_upgrade::<Runtime>());
+		migrations::migrate::<Runtime>();
+		assert_ok!(migrations::post_upgrade::<Runtime>());
+
+		assert_eq!(StorageVersion::<Runtime>::get(), Releases::V4_0_0);
+		assert_eq!(
+			PerpetualsExchange::collateral_params(DOT),
+			AssetParams {
+				initial_im_ratio: Permill::from_percent(20),
+				liquidation_ratio: Permill::from_percent(10),
+				transaction_fee: Permill::from_parts(1000),
+				matching_policy: MatchingPolicy::LargestRemainder,
+				vault_risk_limit: 50,
+				payoff: Payoff::Linear,
+				brackets: vec![],
+			}
+		);
+		assert_eq!(PerpetualsExchange::collateral_params(LDOT).payoff, Payoff::Linear);
+	});
+}
+
+#[test]
+fn payoffs_works() {
+	let two: FixedU128 = 2u128.into();
+	let four: FixedU128 = 4u128.into();
+	let half = FixedU128::saturating_from_rational(1, 2);
+	let quarter = FixedU128::saturating_from_rational(1, 4);
+
+	assert_eq!(Payoff::Linear.value(two), Some(two));
+	assert_eq!(Payoff::Linear.change(two, four), Some((true, two)));
+	assert_eq!(Payoff::Linear.change(four, two), Some((false, two)));
+
+	assert_eq!(Payoff::Inverse.value(two), Some(half));
+	assert_eq!(Payoff::Inverse.value(FixedU128::zero()), None);
+	// 100 units paying 1/2 of the asset are worth 100 of margin
+	assert_eq!(Payoff::Inverse.margin_value(two, 100), Some(100));
+	assert_eq!(Payoff::Linear.margin_value(two, 100), Some(200));
+	// A long gains 1/4 of the asset from 2 to 4, worth 1 at 4
+	assert_eq!(Payoff::Inverse.margin_change(two, four), Some((true, FixedU128::one())));
+	assert_eq!(Payoff::Linear.margin_change(two, four), Some((true, two)));
+	// A long gains 1/2 - 1/4 when the price goes from 2 to 4
+	assert_eq!(Payoff::Inverse.change(two, four), Some((true, quarter)));
+	assert_eq!(Payoff::Inverse.change(four, two), Some((false, quarter)));
+	// 1/3 is rounded up
+	assert_eq!(
+		Payoff::Inverse.value(3u128.into()),
+		Some(FixedU128::from_inner(333_333_333_333_333_334))
+	);
+
+	assert_eq!(Payoff::Quadratic.value(two), Some(four));
+	assert_eq!(Payoff::Quadratic.change(two, four), Some((true, 12u128.into())));
+	assert_eq!(Payoff::Quadratic.value(FixedU128::from_inner(u128::MAX)), None);
+
+	let capped = Payoff::Capped { cap: 3u128.into() };
+	assert_eq!(capped.value(two), Some(two));
+	assert_eq!(capped.value(four), Some(3u128.into()));
+	assert_eq!(capped.change(two, four), Some((true, FixedU128::one())));
+	assert_eq!(capped.change(four, 10u128.into()), Some((false, FixedU128::zero())));
+}
+
+#[test]
+fn quadratic_market_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		System::reset_events();
+		CollateralParams::<Runtime>::mutate(DOT, |params| params.payoff = Payoff::Quadratic);
+		MockPriceSource::set_price(DOT, Some(2u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		// A unit is worth 4, so 100 units need 80 of IM
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 80i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 81i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 201i128, None));
+		PerpetualsExchange::on_initialize(2);
+
+		// A unit goes from 4 to 9
+		MockPriceSource::set_price(DOT, Some(3u128.into()));
+		PerpetualsExchange::on_initialize(3);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 580u128);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 0u128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &BOB), 0i128);
+
+		// Inverse markets halt at a zero price instead of dividing by it
+		CollateralParams::<Runtime>::mutate(LDOT, |params| params.payoff = Payoff::Inverse);
+		MockPriceSource::set_price(LDOT, Some(FixedU128::zero()));
+		PerpetualsExchange::on_initialize(4);
+		assert!(PerpetualsExchange::market_halted(LDOT));
+	});
+}
+
+#[test]
+fn dated_futures_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_expiry(Origin::signed(BOB), DOT, Some(10), Some(LDOT)),
+			BadOrigin
+		);
+		assert_noop!(
+			PerpetualsExchange::set_expiry(Origin::signed(ALICE), DOT, Some(1), Some(LDOT)),
+			crate::Error::<Runtime>::InvalidExpiry
+		);
+		assert_ok!(PerpetualsExchange::set_expiry(
+			Origin::signed(ALICE),
+			DOT,
+			Some(10),
+			Some(LDOT)
+		));
+		assert_eq!(PerpetualsExchange::expiry(DOT), Some((10, Some(LDOT))));
+
+		// Rolling closes the position in DOT and opens it in LDOT
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 50i128, 100i128, None));
+		assert_ok!(PerpetualsExchange::roll_position(Origin::signed(CHARLIE), DOT));
+		assert_eq!(PerpetualsExchange::balances(DOT, &CHARLIE), 0i128);
+		assert_eq!(PerpetualsExchange::balances(LDOT, &CHARLIE), 50i128);
+		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 97u128); // 1 + 1 + 1 in fees
+		assert_noop!(
+			PerpetualsExchange::roll_position(Origin::signed(CHARLIE), DOT),
+			crate::Error::<Runtime>::NothingToRoll
+		);
+		assert_noop!(
+			PerpetualsExchange::roll_position(Origin::signed(CHARLIE), LDOT),
+			crate::Error::<Runtime>::NoRollTarget
+		);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 100i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 1000i128, None));
+		PerpetualsExchange::on_initialize(2);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+
+		// Prices of the last 3 blocks before expiry are averaged
+		for &(block, price) in &[(7u64, 2u128), (8, 3), (9, 4)] {
+			System::set_block_number(block);
+			MockPriceSource::set_price(DOT, Some(price.into()));
+			PerpetualsExchange::on_initialize(block);
+		}
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 399u128);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 699u128);
+
+		System::set_block_number(10);
+		MockPriceSource::set_price(DOT, Some(5u128.into()));
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 100i128, None),
+			crate::Error::<Runtime>::MarketExpired
+		);
+		PerpetualsExchange::on_initialize(10);
+
+		assert_eq!(PerpetualsExchange::settlement_price(DOT), Some(3u128.into()));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 299u128);
+		assert_eq!(PerpetualsExchange::margin(&BOB), 799u128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &BOB), 0i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).realised_pnl, 200i128);
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::MarketSettled(DOT, 3u128.into()))));
+
+		// A settled market is no longer marked
+		MockPriceSource::set_price(DOT, Some(6u128.into()));
+		PerpetualsExchange::on_initialize(11);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 299u128);
+		assert_noop!(
+			PerpetualsExchange::set_expiry(Origin::signed(ALICE), DOT, None, None),
+			crate::Error::<Runtime>::MarketExpired
+		);
+	});
+}
+
+#[test]
+fn portfolio_margin_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_offset_groups(
+				Origin::signed(ALICE),
+				vec![OffsetGroup { assets: vec![DOT], offset: Permill::one() }]
+			),
+			crate::Error::<Runtime>::InvalidOffsetGroup
+		);
+		assert_noop!(
+			PerpetualsExchange::set_offset_groups(
+				Origin::signed(ALICE),
+				vec![OffsetGroup { assets: vec![DOT, KUSD], offset: Permill::one() }]
+			),
+			crate::Error::<Runtime>::InvalidOffsetGroup
+		);
+
+		// Long DOT needs 200 and short LDOT 300, 500 if unrelated
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 1000i128, 301i128, None));
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, -1000i128, 20i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+
+		// Fully offset, only the larger leg is margined
+		assert_ok!(PerpetualsExchange::set_offset_groups(
+			Origin::signed(ALICE),
+			vec![OffsetGroup { assets: vec![DOT, LDOT], offset: Permill::one() }]
+		));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, -1000i128, 20i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 300u128);
+		assert_eq!(
+			PerpetualsExchange::maintenance_margins(&ALICE, &[DOT, LDOT]),
+			Some((0, 100))
+		);
+
+		// Half offset
+		assert_ok!(PerpetualsExchange::set_offset_groups(
+			Origin::signed(ALICE),
+			vec![OffsetGroup { assets: vec![DOT, LDOT], offset: Permill::from_percent(50) }]
+		));
+		assert_eq!(
+			PerpetualsExchange::maintenance_margins(&ALICE, &[DOT, LDOT]),
+			Some((0, 150))
+		);
+
+		// Without groups each asset is margined on its own
+		assert_ok!(PerpetualsExchange::set_offset_groups(Origin::signed(ALICE), vec![]));
+		assert_eq!(
+			PerpetualsExchange::maintenance_margins(&ALICE, &[DOT, LDOT]),
+			Some((0, 200))
+		);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 1i128, 1i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+	});
+}
+
+#[test]
+fn risk_brackets_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		let bracket = |min_notional, im, liquidation| RiskBracket {
+			min_notional,
+			initial_im_ratio: Permill::from_percent(im),
+			liquidation_ratio: Permill::from_percent(liquidation),
+		};
+		for brackets in vec![
+			vec![bracket(1000, 15, 10)],
+			vec![bracket(1000, 50, 25), bracket(1000, 60, 30)],
+			vec![bracket(1000, 50, 25), bracket(2000, 40, 30)],
+			vec![bracket(1000, 30, 30)],
+		] {
+			assert_noop!(
+				PerpetualsExchange::set_global_params(
+					Origin::signed(ALICE),
+					DOT,
+					Change::NoChange,
+					Change::NoChange,
+					Change::NoChange,
+					Change::NewValue(brackets)
+				),
+				crate::Error::<Runtime>::BadIMParameters
+			);
+		}
+		assert_ok!(PerpetualsExchange::set_global_params(
+			Origin::signed(ALICE),
+			DOT,
+			Change::NoChange,
+			Change::NoChange,
+			Change::NoChange,
+			Change::NewValue(vec![bracket(1000, 50, 25)])
+		));
+		// The base ratios cannot go above the first bracket
+		assert_noop!(
+			PerpetualsExchange::set_global_params(
+				Origin::signed(ALICE),
+				DOT,
+				Change::NewValue(Permill::from_percent(60)),
+				Change::NoChange,
+				Change::NoChange,
+				Change::NoChange
+			),
+			crate::Error::<Runtime>::BadIMParameters
+		);
+
+		// Below the bracket, 20% IM and 10% maintenance
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 999i128, 201i128, None));
+		assert_eq!(PerpetualsExchange::maintenance_margins(&ALICE, &[DOT]), Some((0, 100)));
+
+		// The bracket ratios only apply to the notional above 1000, so the
+		// requirements do not jump at the boundary
+		let params = PerpetualsExchange::collateral_params(DOT);
+		assert_eq!(params.requirements(999, Permill::zero()), (200, 100));
+		assert_eq!(params.requirements(1000, Permill::zero()), (200, 100));
+		assert_eq!(params.requirements(1001, Permill::zero()), (201, 101));
+		assert_eq!(params.requirements(1000, Permill::from_percent(30)), (300, 100));
+
+		// A notional of 2000 needs 200 + 500 IM and 100 + 250 maintenance
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 1001i128, 501i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 1001i128, 502i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 700u128);
+		assert_eq!(PerpetualsExchange::maintenance_margins(&ALICE, &[DOT]), Some((0, 350)));
+	});
+}
+
+#[test]
+fn migrate_to_v4_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		use frame_support::storage::unhashed;
+		use migrations::v4::OldAssetParams;
+
+		StorageVersion::<Runtime>::put(Releases::V3_0_0);
+		for (currency_id, payoff) in vec![(DOT, Payoff::Inverse), (LDOT, Payoff::Linear)] {
+			unhashed::put(
+				&CollateralParams::<Runtime>::hashed_key_for(currency_id),
+				&OldAssetParams {
+					initial_im_ratio: Permill::from_percent(20),
+					liquidation_ratio: Permill::from_percent(10),
+					transaction_fee: Permill::from_parts(1000),
+					matching_policy: MatchingPolicy::ProRata,
+					vault_risk_limit: 0,
+					payoff,
+				},
+			);
+		}
+
+		assert_ok!(migrations::pre_upgrade::<Runtime>());
+		migrations::migrate::<Runtime>();
+		assert_ok!(migrations::post_upgrade::<Runtime>());
+
+		assert_eq!(StorageVersion::<Runtime>::get(), Releases::V4_0_0);
+		assert_eq!(PerpetualsExchange::collateral_params(DOT).payoff, Payoff::Inverse);
+		assert_eq!(PerpetualsExchange::collateral_params(DOT).brackets, vec![]);
+		assert_eq!(
+			PerpetualsExchange::collateral_params(LDOT).initial_im_ratio,
+			Permill::from_percent(20)
+		);
+	});
+}
+
+#[test]
+fn leverage_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::set_leverage(Origin::signed(ALICE), KUSD, Some(2u128.into())),
+			crate::Error::<Runtime>::BadAssetID
+		);
+		assert_noop!(
+			PerpetualsExchange::set_leverage(
+				Origin::signed(ALICE),
+				DOT,
+				Some(FixedU128::saturating_from_rational(1, 2))
+			),
+			crate::Error::<Runtime>::InvalidLeverage
+		);
+
+		// 2x needs 50% IM instead of the asset's 20%
+		assert_ok!(PerpetualsExchange::set_leverage(Origin::signed(ALICE), DOT, Some(2u128.into())));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::LeverageUpdated(ALICE, DOT, Some(2u128.into())))
+		);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 50i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 51i128, None));
+		assert_eq!(PerpetualsExchange::account_positions(&ALICE)[0].leverage, Some(2u128.into()));
+
+		// The leverage only applies to the account and the asset it is set for
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, 21i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, 100i128, 32i128, None));
+
+		assert_ok!(PerpetualsExchange::set_leverage(Origin::signed(ALICE), DOT, None));
+		assert_eq!(PerpetualsExchange::leverage(DOT, &ALICE), None);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 1i128, None));
+	});
+}
+
+#[test]
+fn trigger_orders_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 300i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 300i128, None));
+
+		assert_noop!(
+			PerpetualsExchange::place_trigger_order(
+				Origin::signed(ALICE),
+				DOT,
+				8u128.into(),
+				TriggerDirection::Below,
+				0i128,
+				true,
+				None
+			),
+			crate::Error::<Runtime>::InvalidTriggerOrder
+		);
+		// Stop-loss and take-profit
+		assert_ok!(PerpetualsExchange::place_trigger_order(
+			Origin::signed(ALICE),
+			DOT,
+			8u128.into(),
+			TriggerDirection::Below,
+			-1000i128,
+			true,
+			None
+		));
+		assert_ok!(PerpetualsExchange::place_trigger_order(
+			Origin::signed(ALICE),
+			DOT,
+			12u128.into(),
+			TriggerDirection::Above,
+			-100i128,
+			true,
+			None
+		));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::TriggerOrderPlaced(ALICE, DOT, 1))
+		);
+		assert_noop!(
+			PerpetualsExchange::place_trigger_order(
+				Origin::signed(ALICE),
+				DOT,
+				12u128.into(),
+				TriggerDirection::Above,
+				-100i128,
+				true,
+				None
+			),
+			crate::Error::<Runtime>::TooManyTriggerOrders
+		);
+		assert_noop!(
+			PerpetualsExchange::cancel_trigger_order(Origin::signed(BOB), DOT, 1, None),
+			crate::Error::<Runtime>::TriggerOrderNotFound
+		);
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&ALICE), 10u128);
+		assert_ok!(PerpetualsExchange::cancel_trigger_order(Origin::signed(ALICE), DOT, 1, None));
+		assert_eq!(PerpetualsExchange::trigger_order_count(&ALICE), 1);
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&ALICE), 5u128);
+
+		// Fails for lack of margin, and for not reducing
+		assert_ok!(PerpetualsExchange::place_trigger_order(
+			Origin::signed(CHARLIE),
+			DOT,
+			8u128.into(),
+			TriggerDirection::Below,
+			100i128,
+			false,
+			None
+		));
+		assert_ok!(PerpetualsExchange::place_trigger_order(
+			Origin::signed(BOB),
+			DOT,
+			9u128.into(),
+			TriggerDirection::Below,
+			-100i128,
+			true,
+			None
+		));
+
+		PerpetualsExchange::on_initialize(2);
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 100i128);
+		assert!(PerpetualsExchange::trigger_order(DOT, 0).is_some());
+
+		System::reset_events();
+		MockPriceSource::set_price(DOT, Some(8u128.into()));
+		PerpetualsExchange::on_initialize(3);
+
+		// The stop-loss is capped to the position
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::trigger_order(DOT, 0), None);
+		assert_eq!(PerpetualsExchange::trigger_order_count(&ALICE), 0);
+		// Deposits are released whether the orders executed or failed
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&ALICE), 0u128);
+		assert_eq!(<Runtime as Config>::Currency::reserved_balance(&CHARLIE), 0u128);
+		let events: Vec<_> = System::events().into_iter().map(|record| record.event).collect();
+		for event in vec![
+			crate::Event::TriggerOrderTriggered(ALICE, DOT, 0, 8u128.into()),
+			crate::Event::TriggerOrderExecuted(ALICE, DOT, 0, -100),
+			crate::Event::TriggerOrderFailed(CHARLIE, DOT, 2, crate::Error::<Runtime>::NotEnoughIM.into()),
+			crate::Event::TriggerOrderFailed(BOB, DOT, 3, crate::Error::<Runtime>::NotReducing.into()),
+		] {
+			assert!(events.contains(&Event::ecosystem_perpetuals_exchange(event)));
+		}
+		assert_eq!(PerpetualsExchange::balances(DOT, &BOB), -100i128);
+	});
+}
+
+#[test]
+fn transfer_position_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 301i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 300i128, None));
+		System::set_block_number(2);
+		PerpetualsExchange::on_initialize(2);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 100i128);
+
+		// The recipient has to approve the transfer, and pallet accounts
+		// cannot receive positions
+		assert_noop!(
+			PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, CHARLIE, 50i128),
+			crate::Error::<Runtime>::TransferNotApproved
+		);
+		assert_noop!(
+			PerpetualsExchange::transfer_position(
+				Origin::signed(ALICE),
+				DOT,
+				PerpetualsExchange::vault_account(),
+				50i128
+			),
+			crate::Error::<Runtime>::InvalidTransfer
+		);
+		assert_noop!(
+			PerpetualsExchange::transfer_position(
+				Origin::signed(ALICE),
+				DOT,
+				PerpetualsExchange::position_token_account(7),
+				50i128
+			),
+			crate::Error::<Runtime>::InvalidTransfer
+		);
+		assert_ok!(PerpetualsExchange::approve_position_transfer(
+			Origin::signed(CHARLIE),
+			ALICE,
+			DOT,
+			40i128
+		));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::PositionTransferApproved(CHARLIE, ALICE, DOT, 40))
+		);
+		assert_noop!(
+			PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, CHARLIE, 50i128),
+			crate::Error::<Runtime>::TransferNotApproved
+		);
+		assert_ok!(PerpetualsExchange::approve_position_transfer(
+			Origin::signed(CHARLIE),
+			ALICE,
+			DOT,
+			60i128
+		));
+
+		for (to, amount) in vec![(ALICE, 50i128), (CHARLIE, -50), (CHARLIE, 0), (CHARLIE, 101)] {
+			assert_noop!(
+				PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, to, amount),
+				crate::Error::<Runtime>::InvalidTransfer
+			);
+		}
+		Quarantined::<Runtime>::insert(GEORGES, true);
+		assert_noop!(
+			PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, GEORGES, 50i128),
+			crate::Error::<Runtime>::AccountIsQuarantined
+		);
+
+		// Half of the position needs half of ALICE's IM, so half its margin
+		// moves with it, and no fee is paid
+		assert_ok!(PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, CHARLIE, 50i128));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::PositionTransferred(ALICE, DOT, 50, CHARLIE, 150))
+		);
+		assert_eq!(PerpetualsExchange::position_approval(CHARLIE, ALICE), Some((DOT, 10)));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 150u128);
+		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 150u128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 50i128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &CHARLIE), 50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &ALICE), 50i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 50i128);
+		assert_eq!(PerpetualsExchange::position(DOT, &CHARLIE).entry_price, 10u128.into());
+		assert_eq!(PerpetualsExchange::position(DOT, &ALICE).realised_pnl, 0i128);
+		assert_eq!(PerpetualsExchange::order_block(DOT, &CHARLIE), 2);
+		assert_eq!(PerpetualsExchange::open_interest(DOT).longs, 100u128);
+
+		// Both halves are marked
+		MockPriceSource::set_price(DOT, Some(12u128.into()));
+		PerpetualsExchange::on_initialize(3);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 250u128);
+		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 250u128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 50i128);
+	});
+}
+
+#[test]
+fn position_tokens_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 301i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 300i128, None));
+		PerpetualsExchange::on_initialize(2);
+
+		// 40 of the position need 80 of ALICE's 200 IM, so 120 of her margin
+		assert_ok!(PerpetualsExchange::wrap_position(Origin::signed(ALICE), DOT, 40i128));
+		let token_account = PerpetualsExchange::position_token_account(0);
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 60i128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 180u128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &token_account), 40i128);
+		let (owner, position, margin) = PerpetualsExchange::wrapped_position(0).unwrap();
+		assert_eq!((owner, margin), (ALICE, 120u128));
+		assert_eq!(position.map(|position| (position.balance, position.inventory)), Some((40, 40)));
+
+		// The wrapped position is marked like any other
+		MockPriceSource::set_price(DOT, Some(11u128.into()));
+		PerpetualsExchange::on_initialize(3);
+		assert_eq!(PerpetualsExchange::margin(&token_account), 160u128);
+
+		assert_noop!(
+			PerpetualsExchange::transfer_position_token(Origin::signed(BOB), 0, BOB),
+			crate::Error::<Runtime>::NotTokenOwner
+		);
+		assert_noop!(
+			PerpetualsExchange::transfer_position_token(Origin::signed(ALICE), 0, PerpetualsExchange::vault_account()),
+			crate::Error::<Runtime>::InvalidTransfer
+		);
+		assert_noop!(
+			PerpetualsExchange::transfer_position_token(Origin::signed(ALICE), 0, token_account),
+			crate::Error::<Runtime>::InvalidTransfer
+		);
+		assert_ok!(PerpetualsExchange::transfer_position_token(Origin::signed(ALICE), 0, CHARLIE));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::PositionTokenTransferred(0, ALICE, CHARLIE))
+		);
+		assert_noop!(
+			PerpetualsExchange::unwrap_position(Origin::signed(ALICE), 0),
+			crate::Error::<Runtime>::NotTokenOwner
+		);
+
+		assert_ok!(PerpetualsExchange::unwrap_position(Origin::signed(CHARLIE), 0));
+		assert_eq!(PerpetualsExchange::balances(DOT, &CHARLIE), 40i128);
+		assert_eq!(PerpetualsExchange::inventory(DOT, &CHARLIE), 40i128);
+		assert_eq!(PerpetualsExchange::margin(&CHARLIE), 160u128);
+		assert_eq!(PerpetualsExchange::position(DOT, &CHARLIE).entry_price, 10u128.into());
+		assert_eq!(PerpetualsExchange::balances(DOT, &token_account), 0i128);
+		assert!(!Margin::<Runtime>::contains_key(&token_account));
+		assert_eq!(PerpetualsExchange::position_token(0), None);
+		assert_noop!(
+			PerpetualsExchange::unwrap_position(Origin::signed(CHARLIE), 0),
+			crate::Error::<Runtime>::PositionTokenNotFound
+		);
+	});
+}
+
+#[test]
+fn position_token_registry_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		MockPriceSource::set_price(DOT, Some(10u128.into()));
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 301i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, 300i128, None));
+		PerpetualsExchange::on_initialize(2);
+		assert_ok!(PerpetualsExchange::wrap_position(Origin::signed(ALICE), DOT, 40i128));
+		assert_ok!(PerpetualsExchange::wrap_position(Origin::signed(BOB), DOT, -40i128));
+
+		// Another pallet reads and moves tokens through the registry only
+		assert_eq!(
+			<PerpetualsExchange as PositionTokenRegistry<_>>::token(0),
+			Some((ALICE, DOT))
+		);
+		assert_eq!(
+			<PerpetualsExchange as PositionTokenRegistry<_>>::holdings(0),
+			Some((40i128, 120u128))
+		);
+		assert_eq!(<PerpetualsExchange as PositionTokenRegistry<_>>::holdings(2), None);
+		assert_eq!(MockLender::borrow::<PerpetualsExchange>(&ALICE, 0), Ok(60u128));
+		assert_eq!(PerpetualsExchange::position_token(0).map(|token| token.owner), Some(MockLender::account()));
+		assert!(MockLender::borrow::<PerpetualsExchange>(&BOB, 1).is_err());
+		assert_noop!(
+			MockLender::borrow::<PerpetualsExchange>(&BOB, 0),
+			crate::Error::<Runtime>::NotTokenOwner
+		);
+
+		// The same checks as transfer_position_token apply
+		assert_noop!(
+			<PerpetualsExchange as PositionTokenRegistry<_>>::transfer(
+				0,
+				&MockLender::account(),
+				&PerpetualsExchange::vault_account()
+			),
+			crate::Error::<Runtime>::InvalidTransfer
+		);
+		assert_ok!(MockLender::repay::<PerpetualsExchange>(&ALICE, 0));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::PositionTokenTransferred(
+				0,
+				MockLender::account(),
+				ALICE
+			))
+		);
+		assert_ok!(PerpetualsExchange::unwrap_position(Origin::signed(ALICE), 0));
+	});
+}
+
+#[test]
+fn delegation_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		let delegation = |actions: Vec<DelegateAction>| Delegation {
+			actions,
+			assets: vec![DOT],
+			expiry: Some(5),
+		};
+		assert_ok!(PerpetualsExchange::set_delegation(
+			Origin::signed(ALICE),
+			BOB,
+			delegation(vec![DelegateAction::Trade])
+		));
+
+		// BOB trades for ALICE, with ALICE's funds
+		let bob_free = PerpetualsExchange::total_treasury_balance(&BOB);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, 21i128, Some(ALICE)));
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 100i128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 20u128);
+		assert_eq!(PerpetualsExchange::balances(DOT, &BOB), 0i128);
+		assert_eq!(PerpetualsExchange::total_treasury_balance(&BOB), bob_free);
+
+		// But cannot withdraw, trade other assets, and others cannot trade
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, -19i128, Some(ALICE)),
+			crate::Error::<Runtime>::NotDelegated
+		);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(BOB), LDOT, 100i128, 100i128, Some(ALICE)),
+			crate::Error::<Runtime>::NotDelegated
+		);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(CHARLIE), DOT, 100i128, 100i128, Some(ALICE)),
+			crate::Error::<Runtime>::NotDelegated
+		);
+
+		assert_ok!(PerpetualsExchange::place_trigger_order(
+			Origin::signed(BOB),
+			DOT,
+			2u128.into(),
+			TriggerDirection::Above,
+			-100i128,
+			true,
+			Some(ALICE)
+		));
+		assert_eq!(PerpetualsExchange::trigger_order(DOT, 0).map(|order| order.account), Some(ALICE));
+		assert_ok!(PerpetualsExchange::cancel_trigger_order(Origin::signed(BOB), DOT, 0, Some(ALICE)));
+
+		assert_ok!(PerpetualsExchange::set_delegation(
+			Origin::signed(ALICE),
+			BOB,
+			delegation(vec![DelegateAction::Trade, DelegateAction::Withdraw])
+		));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(BOB), DOT, -100i128, -19i128, Some(ALICE)));
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 0i128);
+
+		// The delegation expires after block 5
+		System::set_block_number(6);
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(BOB), DOT, 100i128, 21i128, Some(ALICE)),
+			crate::Error::<Runtime>::NotDelegated
+		);
+
+		assert_ok!(PerpetualsExchange::revoke_delegation(Origin::signed(ALICE), BOB));
+		assert_eq!(PerpetualsExchange::delegation(&ALICE, &BOB), None);
+		assert_noop!(
+			PerpetualsExchange::revoke_delegation(Origin::signed(ALICE), BOB),
+			crate::Error::<Runtime>::NotDelegated
+		);
+	});
+}
+
+#[test]
+fn batch_mint_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_noop!(
+			PerpetualsExchange::batch_mint(Origin::signed(ALICE), vec![], 100i128, None),
+			crate::Error::<Runtime>::InvalidBatch
+		);
+		assert_noop!(
+			PerpetualsExchange::batch_mint(Origin::signed(ALICE), vec![(DOT, 10i128), (DOT, 10i128)], 100i128, None),
+			crate::Error::<Runtime>::InvalidBatch
+		);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 1000i128, 201i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 200u128);
+
+		// Opening LDOT first needs 350 on its own
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), LDOT, 500i128, 0i128, None),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+
+		// Only the final portfolio is checked, and it is all or nothing
+		assert_noop!(
+			PerpetualsExchange::batch_mint(
+				Origin::signed(ALICE),
+				vec![(LDOT, 1000i128), (DOT, -1000i128)],
+				0i128,
+				None
+			),
+			crate::Error::<Runtime>::NotEnoughIM
+		);
+		assert_ok!(PerpetualsExchange::batch_mint(
+			Origin::signed(ALICE),
+			vec![(LDOT, 500i128), (DOT, -1000i128)],
+			0i128,
+			None
+		));
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 0i128);
+		assert_eq!(PerpetualsExchange::balances(LDOT, &ALICE), 500i128);
+		// Fees of 10 on LDOT and 1 on DOT
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 189u128);
+
+		// Collateral comes in before the legs are traded
+		assert_ok!(PerpetualsExchange::batch_mint(
+			Origin::signed(BOB),
+			vec![(DOT, 100i128), (LDOT, -100i128)],
+			53i128,
+			None
+		));
+		assert_eq!(PerpetualsExchange::margin(&BOB), 50u128);
+
+		// A delegate needs rights on every asset traded
+		assert_ok!(PerpetualsExchange::set_delegation(
+			Origin::signed(ALICE),
+			BOB,
+			Delegation {
+				actions: vec![DelegateAction::Trade],
+				assets: vec![DOT],
+				expiry: None,
+			}
+		));
+		assert_noop!(
+			PerpetualsExchange::batch_mint(
+				Origin::signed(BOB),
+				vec![(DOT, 100i128), (LDOT, -100i128)],
+				0i128,
+				Some(ALICE)
+			),
+			crate::Error::<Runtime>::NotDelegated
+		);
+		assert_ok!(PerpetualsExchange::batch_mint(Origin::signed(BOB), vec![(DOT, 100i128)], 21i128, Some(ALICE)));
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 100i128);
+	});
+}
+
+#[test]
+fn withdrawal_limits_works() {
+	ExtBuilder::default().build().execute_with(|| {
+		System::set_block_number(1);
+		PerpetualsExchange::on_initialize(1);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 121i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 120u128);
+
+		let limit = |timelock| WithdrawalLimit { cap: 50, timelock };
+		assert_ok!(PerpetualsExchange::set_withdrawal_limit(Origin::signed(ALICE), Some(limit(Some(5)))));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::WithdrawalLimitUpdated(ALICE, Some(limit(Some(5)))))
+		);
+
+		// Within the cap withdrawals go through, above it they are queued,
+		// the trade still going through
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -30i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 10i128, -40i128, None));
+		assert!(System::events().iter().any(|record| record.event
+			== Event::ecosystem_perpetuals_exchange(crate::Event::WithdrawalQueued(ALICE, 0, 40, 6))));
+		assert_eq!(
+			PerpetualsExchange::pending_withdrawal(&ALICE, 0),
+			Some(PendingWithdrawal {
+				amount: 40,
+				unlock_at: 6,
+			})
+		);
+		assert_eq!(PerpetualsExchange::balances(DOT, &ALICE), 110i128);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 89u128);
+		assert_noop!(
+			PerpetualsExchange::execute_withdrawal(Origin::signed(ALICE), 0),
+			crate::Error::<Runtime>::WithdrawalLocked
+		);
+
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -30i128, None));
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -30i128, None),
+			crate::Error::<Runtime>::TooManyPendingWithdrawals
+		);
+		assert_ok!(PerpetualsExchange::cancel_withdrawal(Origin::signed(ALICE), 1));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::WithdrawalCancelled(ALICE, 1))
+		);
+		assert_eq!(PerpetualsExchange::pending_withdrawal_count(&ALICE), 1);
+		assert_noop!(
+			PerpetualsExchange::cancel_withdrawal(Origin::signed(ALICE), 1),
+			crate::Error::<Runtime>::WithdrawalNotFound
+		);
+		assert_noop!(
+			PerpetualsExchange::execute_withdrawal(Origin::signed(BOB), 0),
+			crate::Error::<Runtime>::WithdrawalNotFound
+		);
+
+		// A stricter limit applies right away, and without a timelock
+		// withdrawals over the cap are rejected
+		assert_ok!(PerpetualsExchange::set_withdrawal_limit(Origin::signed(ALICE), Some(limit(None))));
+		assert_eq!(PerpetualsExchange::withdrawal_limit(&ALICE), Some(limit(None)));
+		assert_noop!(
+			PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -30i128, None),
+			crate::Error::<Runtime>::WithdrawalLimitExceeded
+		);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -20i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 69u128);
+
+		// A looser one waits for a withdrawal period, as there is no timelock
+		assert_ok!(PerpetualsExchange::set_withdrawal_limit(Origin::signed(ALICE), Some(limit(Some(5)))));
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::WithdrawalLimitChangeQueued(
+				ALICE,
+				Some(limit(Some(5))),
+				11
+			))
+		);
+		assert_eq!(PerpetualsExchange::withdrawal_limit(&ALICE), Some(limit(None)));
+
+		// The withdrawal only touches the margin, so it goes through even
+		// if the market it was requested through is halted
+		System::set_block_number(6);
+		HaltedMarkets::<Runtime>::insert(DOT, true);
+		assert_ok!(PerpetualsExchange::execute_withdrawal(Origin::signed(ALICE), 0));
+		HaltedMarkets::<Runtime>::remove(DOT);
+		assert_eq!(
+			last_event(),
+			Event::ecosystem_perpetuals_exchange(crate::Event::WithdrawalExecuted(ALICE, 0, 40))
+		);
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 29u128);
+		assert_eq!(PerpetualsExchange::pending_withdrawal(&ALICE, 0), None);
+		assert_eq!(PerpetualsExchange::pending_withdrawal_count(&ALICE), 0);
+
+		// The cap frees up linearly over the withdrawal period: 9 blocks
+		// later 45 of the 50 withdrawn no longer count
+		System::set_block_number(10);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -7i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 22u128);
+		assert_eq!(PerpetualsExchange::withdrawn_collateral(&ALICE), (10, 12));
+		assert_eq!(PerpetualsExchange::count_withdrawal(&ALICE, &limit(None), 39), Ok(false));
+
+		// A withdrawal period of 0 disables the caps
+		WithdrawalPeriod::set(0);
+		assert_eq!(PerpetualsExchange::count_withdrawal(&ALICE, &limit(None), 1000), Ok(true));
+		WithdrawalPeriod::set(10);
+
+		// Removing the limit replaces the queued change
+		assert_ok!(PerpetualsExchange::set_withdrawal_limit(Origin::signed(ALICE), None));
+		assert_eq!(PerpetualsExchange::pending_withdrawal_limit(&ALICE), Some((None, 20)));
+		assert_eq!(PerpetualsExchange::withdrawal_limit(&ALICE), Some(limit(None)));
+
+		System::set_block_number(20);
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, -110i128, 100i128, None));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 0i128, -60i128, None));
+		assert_eq!(PerpetualsExchange::margin(&ALICE), 61u128);
+		assert_eq!(PerpetualsExchange::withdrawal_limit(&ALICE), None);
+
+		// Margin moved out with a position or a position token counts
+		// against the cap
+		assert_ok!(PerpetualsExchange::set_withdrawal_limit(
+			Origin::signed(ALICE),
+			Some(WithdrawalLimit { cap: 0, timelock: None })
+		));
+		assert_ok!(PerpetualsExchange::mint(Origin::signed(ALICE), DOT, 100i128, 0i128, None));
+		assert_ok!(PerpetualsExchange::approve_position_transfer(
+			Origin::signed(BOB),
+			ALICE,
+			DOT,
+			50i128
+		));
+		assert_noop!(
+			PerpetualsExchange::transfer_position(Origin::signed(ALICE), DOT, BOB, 50i128),
+			crate::Error::<Runtime>::WithdrawalLimitExceeded
+		);
+		assert_ok!(PerpetualsExchange::wrap_position(Origin::signed(ALICE), DOT, 50i128));
+		assert_noop!(
+			PerpetualsExchange::transfer_position_token(Origin::signed(ALICE), 0, BOB),
+			crate::Error::<Runtime>::WithdrawalLimitExceeded
+		);
+	});
+}